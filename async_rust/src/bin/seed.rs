@@ -0,0 +1,265 @@
+//! Fill the database with synthetic recipes to benchmark against.
+//!
+//! ```sh
+//! cargo run --features seed --bin seed -- 1000
+//! ```
+//!
+//! This truncates the recipe tables first, so only point it at a throwaway
+//! database with the recipeyak schema migrated. Reruns give the same data.
+
+#[cfg(not(debug_assertions))]
+compile_error!("the seed binary wipes the recipe tables, it's not for release builds");
+
+use chrono::{Duration, Utc};
+use dotenvy::dotenv;
+use native_tls::{Certificate, TlsConnector};
+use postgres_native_tls::MakeTlsConnector;
+use std::{env, fs};
+
+const EMAIL: &str = "seed@example.com";
+const SESSION_KEY: &str = "seedsessionkey0000000000000000000";
+/// What `core_recipe.content_type_id` is for recipes owned by a user.
+const USER_CONTENT_TYPE: i32 = 1;
+
+const NAMES: [&str; 8] = [
+    "Pasta", "Curry", "Soup", "Salad", "Tacos", "Risotto", "Stew", "Pie",
+];
+const INGREDIENTS: [&str; 10] = [
+    "onion",
+    "garlic",
+    "olive oil",
+    "salt",
+    "pepper",
+    "butter",
+    "flour",
+    "stock",
+    "lemon",
+    "parsley",
+];
+const TAGS: [&str; 6] = [
+    "dinner",
+    "quick",
+    "vegetarian",
+    "spicy",
+    "baking",
+    "weeknight",
+];
+const EMOJI: [&str; 4] = ["👍", "😋", "🔥", "❤️"];
+
+/// A small LCG, good enough to spread the data out and deterministic so the
+/// runs are comparable.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((self.0 >> 33) % n as u64) as usize
+    }
+
+    /// Between `low` and `high`, inclusive.
+    fn between(&mut self, low: usize, high: usize) -> usize {
+        low + self.below(high - low + 1)
+    }
+}
+
+fn position(i: usize) -> String {
+    format!("{:04}", i * 10)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    dotenv().ok();
+    let count: usize = match env::args().nth(1) {
+        Some(count) => count.parse()?,
+        None => 1000,
+    };
+
+    let dsn = env::var("PG_DSN")?;
+    let cert = Certificate::from_pem(&fs::read("database_cert.pem")?)?;
+    let connector =
+        MakeTlsConnector::new(TlsConnector::builder().add_root_certificate(cert).build()?);
+    let (mut client, connection) = tokio_postgres::connect(&dsn, connector).await?;
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            eprintln!("connection error: {}", err);
+        }
+    });
+
+    let tx = client.transaction().await?;
+    tx.batch_execute(
+        r#"
+TRUNCATE "core_reaction", "core_note", "timeline_event", "core_ingredient", "core_step", "core_section", "core_recipe" RESTART IDENTITY CASCADE;
+"#,
+    )
+    .await?;
+
+    let now = Utc::now();
+    let user_id: i32 = tx
+        .query_one(
+            r#"
+INSERT INTO "core_myuser" ("email", "name", "password", "is_active", "is_admin", "created", "modified")
+	VALUES($1, 'Seed User', '!', TRUE, FALSE, $2, $2)
+ON CONFLICT ("email")
+	DO UPDATE SET
+		"is_active" = TRUE
+	RETURNING
+		"core_myuser"."id";
+"#,
+            &[&EMAIL, &now],
+        )
+        .await?
+        .get("id");
+    tx.execute(
+        r#"
+INSERT INTO "user_sessions_session" ("session_key", "session_data", "expire_date", "user_id", "user_agent", "last_activity", "ip")
+	VALUES($1, '', $2, $3, 'seed', $4, '127.0.0.1')
+ON CONFLICT ("session_key")
+	DO UPDATE SET
+		"expire_date" = EXCLUDED. "expire_date", "user_id" = EXCLUDED. "user_id";
+"#,
+        &[&SESSION_KEY, &(now + Duration::days(365)), &user_id, &now],
+    )
+    .await?;
+
+    let insert_recipe = tx
+        .prepare(
+            r#"
+INSERT INTO "core_recipe" ("name", "author", "source", "time", "servings", "tags", "edits", "object_id", "content_type_id", "created", "modified")
+	VALUES($1, 'Seed User', 'https://example.com', '1 hour', '4 servings', $2, 0, $3, $4, $5, $5)
+	RETURNING
+		"core_recipe"."id";
+"#,
+        )
+        .await?;
+    let insert_ingredient = tx
+        .prepare(
+            r#"
+INSERT INTO "core_ingredient" ("quantity", "name", "description", "position", "optional", "recipe_id", "created", "modified")
+	VALUES($1, $2, '', $3, FALSE, $4, $5, $5);
+"#,
+        )
+        .await?;
+    let insert_section = tx
+        .prepare(
+            r#"
+INSERT INTO "core_section" ("title", "position", "recipe_id", "created", "modified")
+	VALUES($1, $2, $3, $4, $4);
+"#,
+        )
+        .await?;
+    let insert_step = tx
+        .prepare(
+            r#"
+INSERT INTO "core_step" ("text", "position", "recipe_id", "created", "modified")
+	VALUES($1, $2, $3, $4, $4);
+"#,
+        )
+        .await?;
+    let insert_note = tx
+        .prepare(
+            r#"
+INSERT INTO "core_note" ("text", "created", "modified", "recipe_id", "created_by_id", "last_modified_by_id")
+	VALUES($1, $2, $2, $3, $4, $4)
+	RETURNING
+		"core_note"."id";
+"#,
+        )
+        .await?;
+    let insert_reaction = tx
+        .prepare(
+            r#"
+INSERT INTO "core_reaction" ("emoji", "created_by_id", "note_id", "created", "modified")
+	VALUES($1, $2, $3, $4, $4);
+"#,
+        )
+        .await?;
+    let insert_event = tx
+        .prepare(
+            r#"
+INSERT INTO "timeline_event" ("action", "created", "created_by_id", "recipe_id")
+	VALUES($1, $2, $3, $4);
+"#,
+        )
+        .await?;
+
+    let mut rng = Rng(42);
+    for i in 0..count {
+        // spread the recipes out over the last few years, newest last
+        let created = now - Duration::hours(((count - i) * 7) as i64);
+        let name = format!("{} #{}", NAMES[rng.below(NAMES.len())], i + 1);
+        let tags: Vec<&str> = (0..rng.below(4))
+            .map(|_| TAGS[rng.below(TAGS.len())])
+            .collect();
+        let recipe_id: i32 = tx
+            .query_one(
+                &insert_recipe,
+                &[&name, &tags, &user_id, &USER_CONTENT_TYPE, &created],
+            )
+            .await?
+            .get("id");
+
+        let ingredient_count = rng.between(3, 15);
+        for j in 0..ingredient_count {
+            let quantity = format!("{} cups", rng.between(1, 4));
+            let ingredient = INGREDIENTS[rng.below(INGREDIENTS.len())];
+            tx.execute(
+                &insert_ingredient,
+                &[&quantity, &ingredient, &position(j), &recipe_id, &created],
+            )
+            .await?;
+        }
+        // the sections sit in between the ingredients
+        for j in 0..rng.below(3) {
+            let title = format!("Part {}", j + 1);
+            let position = format!("{}5", position(rng.below(ingredient_count)));
+            tx.execute(&insert_section, &[&title, &position, &recipe_id, &created])
+                .await?;
+        }
+        for j in 0..rng.between(2, 10) {
+            let text = format!("Step {}, keep going until it looks right.", j + 1);
+            tx.execute(&insert_step, &[&text, &position(j), &recipe_id, &created])
+                .await?;
+        }
+        tx.execute(&insert_event, &[&"created", &created, &user_id, &recipe_id])
+            .await?;
+        for j in 0..rng.below(5) {
+            let note_created = created + Duration::hours(j as i64 + 1);
+            let text = format!("Made this again, note {}.", j + 1);
+            let note_id: i32 = tx
+                .query_one(&insert_note, &[&text, &note_created, &recipe_id, &user_id])
+                .await?
+                .get("id");
+            // one reaction per emoji, there's only the one user
+            for emoji in EMOJI.iter().filter(|_| rng.below(3) == 0) {
+                tx.execute(
+                    &insert_reaction,
+                    &[emoji, &user_id, &note_id, &note_created],
+                )
+                .await?;
+            }
+        }
+        if rng.below(10) == 0 {
+            tx.execute(
+                &insert_event,
+                &[
+                    &"archived",
+                    &(created + Duration::days(1)),
+                    &user_id,
+                    &recipe_id,
+                ],
+            )
+            .await?;
+        }
+    }
+    tx.commit().await?;
+
+    println!("seeded {} recipes for {}", count, EMAIL);
+    println!(
+        "curl --cookie 'sessionid={}' localhost:8080/api/v1/recipes",
+        SESSION_KEY
+    );
+    Ok(())
+}
@@ -0,0 +1,407 @@
+//! All the settings, read from the environment once at startup.
+
+use crate::session::{CookieFormat, SlidingSettings};
+use axum::http::HeaderValue;
+use std::collections::HashMap;
+use std::env::{self, VarError};
+use std::fmt::{self, Display};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+
+pub struct Config {
+    pub pg_dsn: String,
+    /// Reads go here when set, see `ReadPool`
+    pub pg_replica_dsn: Option<String>,
+    /// Root certificate for the database connections
+    pub database_cert_path: PathBuf,
+    pub pool: PoolSettings,
+    /// How long the replica gets to hand out a connection before reads fall
+    /// back to the primary
+    pub replica_connection_timeout: Duration,
+    pub db_max_retries: u32,
+    /// Sent as `Retry-After` on the 503s
+    pub retry_after: Duration,
+    /// Unset unless `RECIPE_CACHE_ENABLED=1`, see `RecipeCache`
+    pub recipe_cache_ttl: Option<Duration>,
+    pub recipe_cache_capacity: u64,
+    pub session_cache_ttl: Option<Duration>,
+    pub session_cache_capacity: u64,
+    /// `SESSION_COOKIE_FORMAT`, `raw` or `django-signed` which needs
+    /// `SECRET_KEY`
+    pub session_cookie_format: CookieFormat,
+    /// `SESSION_SLIDING=1`, off by default
+    pub session_sliding: Option<SlidingSettings>,
+    /// Signs the CSRF tokens, random per process when unset
+    pub secret_key: Option<String>,
+    /// Unset means same-origin only, `*` isn't an option since browsers reject
+    /// it for credentialed (cookie) requests
+    pub cors_allowed_origins: Option<Vec<HeaderValue>>,
+    pub bind: Bind,
+    /// HTTPS when set, otherwise TLS is left to a proxy
+    pub tls: Option<TlsPaths>,
+    pub http: HttpSettings,
+    /// Larger request bodies are refused with a 413
+    pub max_body_bytes: usize,
+    pub log_format: LogFormat,
+    /// `RUST_LOG` directives, or just a level from `LOG_LEVEL`, `info` by
+    /// default. Only the log, the console and OTLP layers see everything.
+    pub log_filter: String,
+    /// Serve the runtime's task data to `tokio-console`, needs the `console`
+    /// feature
+    pub tokio_console: bool,
+    /// Export traces to this OTLP collector, needs the `otel` feature
+    pub otlp_endpoint: Option<String>,
+    /// Serve `/debug/pool`, off in production
+    pub debug_endpoints: bool,
+}
+
+/// `POOL_SIZE`, `POOL_MIN_IDLE`, `POOL_IDLE_TIMEOUT_SECS` and
+/// `POOL_MAX_LIFETIME_SECS`, the `PG_` names they had before still work.
+///
+/// The timeouts default to bb8's: wait up to 30s for a connection, close ones
+/// idle for 10 minutes and recycle every connection after 30 minutes so none
+/// outlive a failover for long. 0 turns the idle timeout or max lifetime off.
+#[derive(Clone, Copy)]
+pub struct PoolSettings {
+    pub max_size: u32,
+    /// Connections kept open however idle, so a burst doesn't have to wait on
+    /// new ones. Unset (0) by default, the pool only grows on demand.
+    pub min_idle: Option<u32>,
+    /// A round trip per checkout, but it means a connection Postgres has since
+    /// closed (restart, failover, idle timeout) gets replaced instead of
+    /// failing the request
+    pub check_on_checkout: bool,
+    pub connection_timeout: Duration,
+    pub idle_timeout: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+    /// Postgres cancels anything slower, so a runaway query or a lock wait
+    /// can't tie up a connection forever. 0 turns it off.
+    pub statement_timeout: Option<Duration>,
+    /// `SLOW_QUERY_MS`, queries slower than this are logged at `warn`. 0 turns
+    /// that off.
+    pub slow_query: Option<Duration>,
+}
+
+pub enum Bind {
+    Tcp(SocketAddr),
+    /// `unix:/path/to/socket`, e.g. behind a local proxy
+    Unix(PathBuf),
+}
+
+pub struct TlsPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// HTTP/2 is served alongside HTTP/1, negotiated with ALPN over TLS or as h2c
+/// with prior knowledge over plain TCP.
+#[derive(Clone, Copy)]
+pub struct HttpSettings {
+    /// Reuse HTTP/1 connections
+    pub http1_keepalive: bool,
+    pub tcp_keepalive: Option<Duration>,
+    /// Ping idle HTTP/2 connections
+    pub http2_keepalive: Option<Duration>,
+    /// Parallel requests per connection
+    pub http2_max_concurrent_streams: u32,
+    /// HTTP/2 only, without TLS, for benchmarks. Plain HTTP/1 requests are
+    /// refused, clients need prior knowledge e.g.
+    /// `curl --http2-prior-knowledge` or `h2load`.
+    pub http2_only: bool,
+}
+
+/// `LOG_FORMAT`.
+#[derive(Clone, Copy)]
+pub enum LogFormat {
+    /// The human readable lines, one per event
+    Pretty,
+    /// A JSON object per line for log pipelines, the span fields included
+    Json,
+}
+
+/// Every variable that's missing or invalid, not just the first.
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Reads the variables, noting the problems as it goes so they can all be
+/// reported at once.
+struct Env {
+    vars: HashMap<String, String>,
+    problems: Vec<String>,
+}
+
+impl Env {
+    fn var(&self, name: &str) -> Result<String, VarError> {
+        self.vars.get(name).cloned().ok_or(VarError::NotPresent)
+    }
+
+    /// `name`, unless only `old` is set, so the problems name the variable
+    /// that was actually used.
+    fn renamed(&self, name: &'static str, old: &'static str) -> &'static str {
+        if self.var(name).is_err() && self.var(old).is_ok() {
+            old
+        } else {
+            name
+        }
+    }
+
+    fn required(&mut self, name: &str) -> String {
+        self.var(name).unwrap_or_else(|_| {
+            self.problems.push(format!("{} is required", name));
+            String::new()
+        })
+    }
+
+    fn parse<T>(&mut self, name: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.var(name) {
+            Ok(value) => value.parse().unwrap_or_else(|err| {
+                self.problems
+                    .push(format!("{} {:?} is invalid: {}", name, value, err));
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    /// A number of `unit`s where 0 means `None`.
+    fn duration(
+        &mut self,
+        name: &str,
+        default: u64,
+        unit: fn(u64) -> Duration,
+    ) -> Option<Duration> {
+        Some(unit(self.parse(name, default))).filter(|duration| !duration.is_zero())
+    }
+
+    /// `BIND_ADDR` if it's set, otherwise `HOST` and `PORT`.
+    fn bind(&mut self) -> Bind {
+        if let Ok(bind_addr) = self.var("BIND_ADDR") {
+            if let Some(path) = bind_addr.strip_prefix("unix:") {
+                return Bind::Unix(path.into());
+            }
+            return Bind::Tcp(bind_addr.parse().unwrap_or_else(|err| {
+                self.problems.push(format!(
+                    "BIND_ADDR {:?} is invalid, it should look like 0.0.0.0:8080, [::]:8080 or unix:/path/to/socket: {}",
+                    bind_addr, err
+                ));
+                SocketAddr::from(([0, 0, 0, 0], 8080))
+            }));
+        }
+        let host = self.var("HOST").unwrap_or_else(|_| "0.0.0.0".into());
+        // IPv6 literals work with or without the brackets
+        let ip: IpAddr = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .unwrap_or_else(|err| {
+                self.problems
+                    .push(format!("HOST {:?} is invalid: {}", host, err));
+                IpAddr::from([0, 0, 0, 0])
+            });
+        Bind::Tcp(SocketAddr::new(ip, self.parse("PORT", 8080)))
+    }
+
+    fn cookie_format(&mut self) -> CookieFormat {
+        match self.var("SESSION_COOKIE_FORMAT").as_deref() {
+            Err(_) | Ok("raw") => CookieFormat::Raw,
+            Ok("django-signed") => CookieFormat::DjangoSigned {
+                secret_key: self.required("SECRET_KEY"),
+            },
+            Ok(format) => {
+                self.problems.push(format!(
+                    "SESSION_COOKIE_FORMAT {:?} is invalid, it should be raw or django-signed",
+                    format
+                ));
+                CookieFormat::Raw
+            }
+        }
+    }
+
+    fn log_format(&mut self) -> LogFormat {
+        match self.var("LOG_FORMAT").as_deref() {
+            Err(_) | Ok("pretty") => LogFormat::Pretty,
+            Ok("json") => LogFormat::Json,
+            Ok(format) => {
+                self.problems.push(format!(
+                    "LOG_FORMAT {:?} is invalid, it should be pretty or json",
+                    format
+                ));
+                LogFormat::Pretty
+            }
+        }
+    }
+
+    /// `RUST_LOG` if it's set, otherwise `LOG_LEVEL`.
+    fn log_filter(&mut self) -> String {
+        match self.var("RUST_LOG") {
+            Ok(directives) => {
+                if let Err(err) = EnvFilter::try_new(&directives) {
+                    self.problems
+                        .push(format!("RUST_LOG {:?} is invalid: {}", directives, err));
+                }
+                directives
+            }
+            Err(_) => self.parse("LOG_LEVEL", LevelFilter::INFO).to_string(),
+        }
+    }
+
+    fn origins(&mut self, name: &str) -> Option<Vec<HeaderValue>> {
+        let origins = self.var(name).ok()?;
+        Some(
+            origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .filter_map(|origin| match origin.parse() {
+                    Ok(origin) => Some(origin),
+                    Err(_) => {
+                        self.problems
+                            .push(format!("{} has an invalid origin {:?}", name, origin));
+                        None
+                    }
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config, ConfigError> {
+        // the ones that aren't unicode can't be any of ours
+        Config::from_vars(env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        }))
+    }
+
+    /// [`Config::from_env`] from a given set of variables rather than the
+    /// process's, for tests.
+    pub fn from_vars(
+        vars: impl IntoIterator<Item = (impl Into<String>, impl Into<String>)>,
+    ) -> Result<Config, ConfigError> {
+        let mut env = Env {
+            vars: vars
+                .into_iter()
+                .map(|(name, value)| (name.into(), value.into()))
+                .collect(),
+            problems: vec![],
+        };
+        let config = Config {
+            pg_dsn: env.required("PG_DSN"),
+            pg_replica_dsn: env.var("PG_REPLICA_DSN").ok(),
+            database_cert_path: env.parse("DATABASE_CERT_PATH", "database_cert.pem".into()),
+            pool: PoolSettings {
+                max_size: env.parse(env.renamed("POOL_SIZE", "PG_POOL_SIZE"), 20),
+                min_idle: Some(env.parse(env.renamed("POOL_MIN_IDLE", "PG_POOL_MIN_IDLE"), 0))
+                    .filter(|min_idle| *min_idle > 0),
+                check_on_checkout: env.parse("PG_CHECK_ON_CHECKOUT", true),
+                connection_timeout: Duration::from_secs(
+                    env.parse("PG_CONNECTION_TIMEOUT_SECS", 30),
+                ),
+                idle_timeout: env.duration(
+                    env.renamed("POOL_IDLE_TIMEOUT_SECS", "PG_IDLE_TIMEOUT_SECS"),
+                    10 * 60,
+                    Duration::from_secs,
+                ),
+                max_lifetime: env.duration(
+                    env.renamed("POOL_MAX_LIFETIME_SECS", "PG_MAX_LIFETIME_SECS"),
+                    30 * 60,
+                    Duration::from_secs,
+                ),
+                statement_timeout: env.duration(
+                    "PG_STATEMENT_TIMEOUT_MS",
+                    30_000,
+                    Duration::from_millis,
+                ),
+                slow_query: env.duration("SLOW_QUERY_MS", 500, Duration::from_millis),
+            },
+            replica_connection_timeout: Duration::from_millis(
+                env.parse("PG_REPLICA_CONNECTION_TIMEOUT_MS", 1000),
+            ),
+            db_max_retries: env.parse("DB_MAX_RETRIES", 2),
+            retry_after: Duration::from_secs(env.parse("RETRY_AFTER_SECS", 1)),
+            // off by default, before RECIPE_CACHE_ENABLED existed setting the
+            // TTL was what turned it on so that still does
+            recipe_cache_ttl: match env.var("RECIPE_CACHE_ENABLED") {
+                Ok(enabled) if enabled == "1" => {
+                    env.duration("RECIPE_CACHE_TTL_SECS", 30, Duration::from_secs)
+                }
+                Ok(_) => None,
+                Err(_) => env.duration("RECIPE_CACHE_TTL_SECS", 0, Duration::from_secs),
+            },
+            recipe_cache_capacity: env.parse("RECIPE_CACHE_CAPACITY", 10_000),
+            // short, it bounds how long a logged out session keeps working. 0
+            // turns it off and every request looks the session up.
+            session_cache_ttl: env.duration("SESSION_CACHE_TTL_SECS", 5, Duration::from_secs),
+            session_cache_capacity: env.parse("SESSION_CACHE_CAPACITY", 10_000),
+            session_cookie_format: env.cookie_format(),
+            session_sliding: env
+                .var("SESSION_SLIDING")
+                .is_ok_and(|sliding| sliding == "1")
+                .then(|| SlidingSettings {
+                    // two weeks, same as Django
+                    window: Duration::from_secs(env.parse("SESSION_COOKIE_AGE", 14 * 24 * 60 * 60)),
+                    interval: Duration::from_secs(env.parse("SESSION_REFRESH_INTERVAL_SECS", 60)),
+                    secure: env
+                        .var("SESSION_COOKIE_SECURE")
+                        .is_ok_and(|secure| secure == "1"),
+                }),
+            secret_key: env.var("SECRET_KEY").ok(),
+            cors_allowed_origins: env.origins("CORS_ALLOWED_ORIGINS"),
+            bind: env.bind(),
+            tls: match (env.var("TLS_CERT_PATH"), env.var("TLS_KEY_PATH")) {
+                (Ok(cert), Ok(key)) => Some(TlsPaths {
+                    cert: cert.into(),
+                    key: key.into(),
+                }),
+                _ => None,
+            },
+            http: HttpSettings {
+                http1_keepalive: env.parse("HTTP_KEEPALIVE", true),
+                tcp_keepalive: env.duration("TCP_KEEPALIVE_SECS", 60, Duration::from_secs),
+                http2_keepalive: env.duration("HTTP2_KEEPALIVE_SECS", 20, Duration::from_secs),
+                http2_max_concurrent_streams: env.parse("HTTP2_MAX_CONCURRENT_STREAMS", 100),
+                http2_only: env.var("HTTP2").is_ok_and(|http2| http2 == "1"),
+            },
+            max_body_bytes: env.parse("MAX_BODY_BYTES", 64 * 1024),
+            log_format: env.log_format(),
+            log_filter: env.log_filter(),
+            tokio_console: env.var("TOKIO_CONSOLE").is_ok_and(|console| console == "1"),
+            otlp_endpoint: env.var("OTEL_EXPORTER_OTLP_ENDPOINT").ok(),
+            debug_endpoints: env.var("DEBUG_ENDPOINTS").is_ok_and(|debug| debug == "1"),
+        };
+        if let Some(min_idle) = config
+            .pool
+            .min_idle
+            .filter(|min| *min > config.pool.max_size)
+        {
+            env.problems.push(format!(
+                "POOL_MIN_IDLE {} is larger than POOL_SIZE {}",
+                min_idle, config.pool.max_size
+            ));
+        }
+        if env.problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(env.problems))
+        }
+    }
+}
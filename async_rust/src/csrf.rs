@@ -0,0 +1,97 @@
+//! CSRF protection for the cookie authenticated writes. A browser sends the
+//! `sessionid` cookie along with a cross-site form post, but a page on
+//! another origin can't read `GET /api/v1/csrf` to learn the token, so
+//! requiring it as an `X-CSRF-Token` header tells the two apart.
+//!
+//! The token is an HMAC of the session key, so it needs no storage and
+//! changes when the session does.
+
+use crate::error::AppError;
+use crate::session::SessionCookies;
+use axum::{
+    extract::State,
+    http::{HeaderName, Method, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+
+pub static X_CSRF_TOKEN: HeaderName = HeaderName::from_static("x-csrf-token");
+
+/// Issues and checks the tokens. Cheap to clone, it's shared through the
+/// `AppState`.
+#[derive(Clone)]
+pub struct Csrf {
+    /// `SECRET_KEY`, or random bytes which don't survive a restart
+    secret: Arc<[u8]>,
+    cookies: SessionCookies,
+}
+
+impl Csrf {
+    pub fn new(secret: Vec<u8>, cookies: SessionCookies) -> Self {
+        Csrf {
+            secret: secret.into(),
+            cookies,
+        }
+    }
+
+    fn mac(&self, session_key: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes keys of any size");
+        mac.update(b"csrf:");
+        mac.update(session_key.as_bytes());
+        mac
+    }
+
+    /// The token for the session the cookie names.
+    pub fn token(&self, session_key: &str) -> String {
+        base64::encode_config(
+            self.mac(session_key).finalize().into_bytes(),
+            base64::URL_SAFE_NO_PAD,
+        )
+    }
+
+    /// Compared in constant time.
+    fn verify(&self, session_key: &str, token: &str) -> bool {
+        base64::decode_config(token, base64::URL_SAFE_NO_PAD)
+            .is_ok_and(|token| self.mac(session_key).verify_slice(&token).is_ok())
+    }
+}
+
+/// Reject a state changing request that has a session cookie but not the
+/// session's token. Requests without a cookie are left to the handler's
+/// 401. `/graphql` and `/api/v1/recipes/batch` are POSTs but only read.
+///
+/// Every handler authenticates with the cookie, so there's no exemption for
+/// an `Authorization` header, a cross-site request could just add one.
+pub async fn protect<B>(
+    State(csrf): State<Csrf>,
+    jar: CookieJar,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let safe = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    if safe || matches!(request.uri().path(), "/graphql" | "/api/v1/recipes/batch") {
+        return next.run(request).await;
+    }
+    let Ok(session_key) = csrf.cookies.session_key(&jar) else {
+        return next.run(request).await;
+    };
+    let token = request
+        .headers()
+        .get(&X_CSRF_TOKEN)
+        .and_then(|token| token.to_str().ok());
+    match token {
+        Some(token) if csrf.verify(&session_key, token) => next.run(request).await,
+        _ => {
+            tracing::info!(method = %request.method(), "CSRF token missing or incorrect");
+            AppError::Forbidden("CSRF token missing or incorrect").into_response()
+        }
+    }
+}
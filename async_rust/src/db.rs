@@ -0,0 +1,309 @@
+use crate::error::AppError;
+use async_trait::async_trait;
+use bb8::{ManageConnection, Pool, PooledConnection};
+use bb8_postgres::PostgresConnectionManager;
+use postgres_native_tls::MakeTlsConnector;
+use std::collections::HashMap;
+use std::error::Error as _;
+use std::future::{Future, IntoFuture};
+use std::io;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio_postgres::types::{FromSql, ToSql};
+use tokio_postgres::{Client, Error, Row, Statement};
+use tracing::Instrument;
+
+pub type ConnectionPool = Pool<PgManager>;
+
+/// Wraps the bb8-postgres manager so each connection carries its own
+/// prepared statement cache.
+///
+/// Statements only live as long as their connection, so when bb8 drops a
+/// broken or recycled connection its cache goes with it and the replacement
+/// starts empty. The flip side is that a schema change which alters a query's
+/// result columns will fail the cached statement until the connection goes
+/// away, so restart the server after migrations.
+pub struct PgManager {
+    manager: PostgresConnectionManager<MakeTlsConnector>,
+    statement_timeout: Option<Duration>,
+    slow_query: Option<Duration>,
+}
+
+impl PgManager {
+    pub fn new(
+        manager: PostgresConnectionManager<MakeTlsConnector>,
+        statement_timeout: Option<Duration>,
+        slow_query: Option<Duration>,
+    ) -> Self {
+        Self {
+            manager,
+            statement_timeout,
+            slow_query,
+        }
+    }
+}
+
+#[async_trait]
+impl ManageConnection for PgManager {
+    type Connection = PgConnection;
+    type Error = Error;
+
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        let client = self.manager.connect().await?;
+        // session level, so it sticks for the lifetime of the connection
+        client.batch_execute("SET TIME ZONE 'UTC'").await?;
+        if let Some(timeout) = self.statement_timeout {
+            client
+                .batch_execute(&format!("SET statement_timeout = {}", timeout.as_millis()))
+                .await?;
+        }
+        Ok(PgConnection {
+            client,
+            statements: Mutex::default(),
+            in_transaction: AtomicBool::new(false),
+            slow_query: self.slow_query,
+        })
+    }
+
+    async fn is_valid(&self, conn: &mut PooledConnection<'_, Self>) -> Result<(), Self::Error> {
+        conn.client.simple_query("SELECT 1").await.map(|_| ())
+    }
+
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        // a transaction that was never finished means the handler was dropped
+        // (e.g. the client went away), so throw the connection out rather than
+        // hand the open transaction to the next request
+        conn.client.is_closed() || conn.in_transaction.load(Ordering::Relaxed)
+    }
+}
+
+/// Where a query's text lives rather than the text itself. The queries are
+/// all literals so each has one fixed address, and the lookup doesn't have
+/// to hash the whole string, some are over a kilobyte.
+type QueryKey = (usize, usize);
+
+fn query_key(query: &'static str) -> QueryKey {
+    (query.as_ptr() as usize, query.len())
+}
+
+pub struct PgConnection {
+    client: Client,
+    statements: Mutex<HashMap<QueryKey, Statement>>,
+    in_transaction: AtomicBool,
+    /// For [`PendingQuery::timed`]
+    slow_query: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum IsolationLevel {
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl PgConnection {
+    /// Prepare `query` the first time it's seen on this connection, after that
+    /// Postgres can skip straight to binding the parameters.
+    pub async fn prepare_cached(&self, query: &'static str) -> Result<Statement, Error> {
+        if let Some(statement) = self.statements.lock().unwrap().get(&query_key(query)) {
+            return Ok(statement.clone());
+        }
+        let start = Instant::now();
+        let statement = self.client.prepare(query).await?;
+        // once per statement per connection, the cost every later call skips
+        tracing::debug!(
+            elapsed_us = start.elapsed().as_micros() as u64,
+            "prepared statement"
+        );
+        self.statements
+            .lock()
+            .unwrap()
+            .insert(query_key(query), statement.clone());
+        Ok(statement)
+    }
+
+    pub async fn begin(&self, isolation: IsolationLevel) -> Result<(), Error> {
+        let query = match isolation {
+            IsolationLevel::ReadCommitted => "BEGIN ISOLATION LEVEL READ COMMITTED",
+            IsolationLevel::RepeatableRead => "BEGIN ISOLATION LEVEL REPEATABLE READ",
+            IsolationLevel::Serializable => "BEGIN ISOLATION LEVEL SERIALIZABLE",
+        };
+        self.client.batch_execute(query).await?;
+        self.in_transaction.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub async fn commit(&self) -> Result<(), Error> {
+        self.client.batch_execute("COMMIT").await?;
+        self.in_transaction.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub async fn rollback(&self) -> Result<(), Error> {
+        self.client.batch_execute("ROLLBACK").await?;
+        self.in_transaction.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // These shadow the `Client` methods of the same name so callers get the
+    // cached statements without having to prepare them by hand.
+
+    pub fn query<'a>(
+        &'a self,
+        query: &'static str,
+        params: &'a [&'a (dyn ToSql + Sync)],
+    ) -> PendingQuery<impl Future<Output = Result<Vec<Row>, Error>> + 'a> {
+        self.pending(async move {
+            let statement = self.prepare_cached(query).await?;
+            self.client.query(&statement, params).await
+        })
+    }
+
+    pub fn query_one<'a>(
+        &'a self,
+        query: &'static str,
+        params: &'a [&'a (dyn ToSql + Sync)],
+    ) -> PendingQuery<impl Future<Output = Result<Row, Error>> + 'a> {
+        self.pending(async move {
+            let statement = self.prepare_cached(query).await?;
+            self.client.query_one(&statement, params).await
+        })
+    }
+
+    pub fn query_opt<'a>(
+        &'a self,
+        query: &'static str,
+        params: &'a [&'a (dyn ToSql + Sync)],
+    ) -> PendingQuery<impl Future<Output = Result<Option<Row>, Error>> + 'a> {
+        self.pending(async move {
+            let statement = self.prepare_cached(query).await?;
+            self.client.query_opt(&statement, params).await
+        })
+    }
+
+    pub fn execute<'a>(
+        &'a self,
+        query: &'static str,
+        params: &'a [&'a (dyn ToSql + Sync)],
+    ) -> PendingQuery<impl Future<Output = Result<u64, Error>> + 'a> {
+        self.pending(async move {
+            let statement = self.prepare_cached(query).await?;
+            self.client.execute(&statement, params).await
+        })
+    }
+
+    fn pending<F: Future>(&self, query: F) -> PendingQuery<F> {
+        PendingQuery {
+            query,
+            slow_query: self.slow_query,
+        }
+    }
+}
+
+impl Deref for PgConnection {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+/// A query from one of the [`PgConnection`] methods, awaiting it runs the query
+/// and so does awaiting [`PendingQuery::timed`].
+pub struct PendingQuery<F> {
+    query: F,
+    /// The connection's `SLOW_QUERY_MS`
+    slow_query: Option<Duration>,
+}
+
+impl<F: Future> PendingQuery<F> {
+    /// Log how long the query took under `label`, at `warn` if it was slower
+    /// than the connection's `SLOW_QUERY_MS` and `debug` otherwise. Logged
+    /// inside the request span so the line carries the request id.
+    ///
+    /// The query also gets a span of its own so an exported trace shows the
+    /// fan-out under the request. It's at `debug`, which the log filters out,
+    /// so without the `otel` layer the span is never created.
+    pub fn timed(self, label: &'static str) -> impl Future<Output = F::Output> {
+        let PendingQuery { query, slow_query } = self;
+        async move {
+            let start = Instant::now();
+            let output = query.await;
+            let elapsed = start.elapsed();
+            let elapsed_ms = elapsed.as_millis() as u64;
+            if slow_query.is_some_and(|slow_query| elapsed >= slow_query) {
+                tracing::warn!(query = label, elapsed_ms, "slow query");
+            } else {
+                tracing::debug!(query = label, elapsed_ms, "query");
+            }
+            output
+        }
+        .instrument(tracing::debug_span!("query", query = label))
+    }
+}
+
+impl<F: Future> IntoFuture for PendingQuery<F> {
+    type Output = F::Output;
+    type IntoFuture = F;
+
+    fn into_future(self) -> F {
+        self.query
+    }
+}
+
+pub trait Columns {
+    /// `Row::try_get`, except a missing or mistyped column is an
+    /// [`AppError::Internal`] naming it rather than a panic, since nothing
+    /// checks the queries against the schema.
+    fn column<'a, T: FromSql<'a>>(&'a self, name: &str) -> Result<T, AppError>;
+}
+
+impl Columns for Row {
+    fn column<'a, T: FromSql<'a>>(&'a self, name: &str) -> Result<T, AppError> {
+        self.try_get(name)
+            .map_err(|err| AppError::Internal(format!("column {}: {}", name, err)))
+    }
+}
+
+/// Whether the error came from the connection rather than the query, e.g. a
+/// reset during a failover, so trying again on another connection could work.
+pub fn is_transient(err: &Error) -> bool {
+    err.is_closed()
+        || err
+            .source()
+            .is_some_and(|source| source.downcast_ref::<io::Error>().is_some())
+}
+
+/// Run `attempt` again while it fails with an error `retryable` accepts, up to
+/// `max_retries` times, backing off exponentially from 50ms.
+///
+/// Only use this for things that are safe to repeat. A write that failed
+/// partway may have been applied.
+pub async fn retry<T, E, F, Fut>(
+    max_retries: u32,
+    retryable: impl Fn(&E) -> bool,
+    mut attempt: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut retries = 0;
+    loop {
+        match attempt().await {
+            Err(err) if retries < max_retries && retryable(&err) => {
+                let backoff = Duration::from_millis(50 * 2u64.pow(retries));
+                tracing::warn!(
+                    retry = retries + 1,
+                    ?backoff,
+                    "retrying after a transient error"
+                );
+                tokio::time::sleep(backoff).await;
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::db;
+use axum::{
+    extract::State,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use std::time::Duration;
+use tokio_postgres::error::SqlState;
+
+/// Everything a handler can fail with, rendered as `{"error": "..."}` like
+/// the panic and unknown route responses.
+#[derive(Debug)]
+pub enum AppError {
+    /// Something's wrong with the request itself, e.g. failed validation
+    BadRequest(String),
+    /// A query parameter that doesn't parse or isn't allowed, the message
+    /// starts with its name
+    Unprocessable(String),
+    /// No session cookie at all, log in
+    SessionMissing,
+    /// The cookie doesn't match any session, log in
+    SessionInvalid,
+    /// The session exists but has expired, log in again
+    SessionExpired,
+    /// The thing exists but the user can't change it
+    Forbidden(&'static str),
+    /// A body in some other format than the endpoint takes
+    UnsupportedMediaType(&'static str),
+    /// A body over `MAX_BODY_BYTES`
+    PayloadTooLarge,
+    NotFound(&'static str),
+    /// Constraint violations are the client's fault so they get a 4xx,
+    /// everything else is on us
+    Db(tokio_postgres::Error),
+    /// Waited too long for a pooled connection
+    PoolTimeout,
+    /// A bug or the schema drifting from the queries, the message is logged
+    /// but not sent
+    Internal(String),
+}
+
+impl AppError {
+    /// For the 401s, so clients can tell a login from a re-login apart
+    /// without matching on the message.
+    pub fn code(&self) -> Option<&'static str> {
+        match self {
+            AppError::SessionMissing => Some("session_missing"),
+            AppError::SessionInvalid => Some("session_invalid"),
+            AppError::SessionExpired => Some("session_expired"),
+            _ => None,
+        }
+    }
+
+    /// The database is down or restarting rather than the request being bad,
+    /// so worth retrying.
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            AppError::PoolTimeout => true,
+            AppError::Db(err) => db::is_transient(err),
+            _ => false,
+        }
+    }
+}
+
+impl From<tokio_postgres::Error> for AppError {
+    fn from(err: tokio_postgres::Error) -> Self {
+        AppError::Db(err)
+    }
+}
+
+impl From<bb8::RunError<tokio_postgres::Error>> for AppError {
+    fn from(err: bb8::RunError<tokio_postgres::Error>) -> Self {
+        match err {
+            bb8::RunError::User(err) => AppError::Db(err),
+            bb8::RunError::TimedOut => AppError::PoolTimeout,
+        }
+    }
+}
+
+impl AppError {
+    /// What the client gets told, logging anything that's on us since the
+    /// message doesn't say much.
+    pub fn status_and_message(self) -> (StatusCode, String) {
+        match self {
+            AppError::SessionMissing => (StatusCode::UNAUTHORIZED, "no session".into()),
+            AppError::SessionInvalid => (StatusCode::UNAUTHORIZED, "unknown session".into()),
+            AppError::SessionExpired => (StatusCode::UNAUTHORIZED, "session expired".into()),
+            AppError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::Unprocessable(message) => (StatusCode::UNPROCESSABLE_ENTITY, message),
+            AppError::Forbidden(message) => (StatusCode::FORBIDDEN, message.into()),
+            AppError::UnsupportedMediaType(message) => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, message.into())
+            }
+            AppError::PayloadTooLarge => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "request body is too large".into(),
+            ),
+            AppError::NotFound(message) => (StatusCode::NOT_FOUND, message.into()),
+            AppError::Db(err) => match err.code() {
+                Some(&SqlState::UNIQUE_VIOLATION | &SqlState::T_R_SERIALIZATION_FAILURE) => {
+                    (StatusCode::CONFLICT, err.to_string())
+                }
+                Some(
+                    &SqlState::FOREIGN_KEY_VIOLATION
+                    | &SqlState::NOT_NULL_VIOLATION
+                    | &SqlState::CHECK_VIOLATION
+                    | &SqlState::STRING_DATA_RIGHT_TRUNCATION,
+                ) => (StatusCode::BAD_REQUEST, err.to_string()),
+                // cancelled by the `statement_timeout`
+                Some(&SqlState::QUERY_CANCELED) => {
+                    tracing::warn!(%err, "query cancelled");
+                    (StatusCode::GATEWAY_TIMEOUT, "query timed out".into())
+                }
+                _ if db::is_transient(&err) => {
+                    tracing::warn!(%err, "lost the database connection");
+                    (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "database unavailable".into(),
+                    )
+                }
+                _ => {
+                    tracing::error!(%err, "database error");
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "internal server error".into(),
+                    )
+                }
+            },
+            AppError::Internal(message) => {
+                tracing::error!(error = %message, "internal error");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal server error".into(),
+                )
+            }
+            AppError::PoolTimeout => {
+                tracing::warn!("timed out waiting for a connection");
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "database unavailable".into(),
+                )
+            }
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let code = self.code();
+        let (status, message) = self.status_and_message();
+        let body = match code {
+            Some(code) => serde_json::json!({ "error": message, "code": code }),
+            None => serde_json::json!({ "error": message }),
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+/// Tell clients how long to back off for, `RETRY_AFTER_SECS`, when the
+/// database is unavailable. Only on the 503s where waiting could help, a 500
+/// will just fail again.
+pub async fn retry_after<B>(
+    State(retry_after): State<Duration>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let mut response = next.run(request).await;
+    if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+        response
+            .headers_mut()
+            .entry(header::RETRY_AFTER)
+            .or_insert(retry_after.as_secs().into());
+    }
+    response
+}
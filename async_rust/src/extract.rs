@@ -0,0 +1,118 @@
+//! Extractors that reject with an [`AppError`] so a bad request gets the same
+//! JSON error as everything else.
+
+use crate::error::AppError;
+use async_trait::async_trait;
+use axum::body::{Bytes, HttpBody};
+use axum::extract::{FromRequest, FromRequestParts};
+use axum::http::{header, request::Parts, HeaderMap, Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::BoxError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::Display;
+
+/// 422 with the path to what didn't deserialize in front of the message.
+/// Syntax errors and missing fields or parameters have no path, for them the
+/// message says where.
+fn unprocessable<E: Display>(err: serde_path_to_error::Error<E>) -> AppError {
+    let path = err.path().to_string();
+    if path == "." {
+        AppError::Unprocessable(err.into_inner().to_string())
+    } else {
+        AppError::Unprocessable(format!("{}: {}", path, err.into_inner()))
+    }
+}
+
+/// Axum's `Query`, except a parameter that doesn't deserialize is a 422 naming
+/// it, e.g. `limit: invalid digit found in string`, instead of a plain text
+/// 400.
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query = parts.uri.query().unwrap_or_default();
+        let deserializer =
+            serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+        serde_path_to_error::deserialize(deserializer)
+            .map(Query)
+            .map_err(unprocessable)
+    }
+}
+
+/// Axum's `Json`, except a body that doesn't deserialize is a 422 naming the
+/// field, e.g. `text: invalid type: integer `1`, expected a string at line 1
+/// column 9`, instead of a plain text 400 or 422. So is anything after the
+/// value. No body at all is a 400, another content type a 415 and a body over
+/// `MAX_BODY_BYTES` a 413.
+///
+/// Responds the same as axum's too, so it can stand in for both.
+pub struct Json<T>(pub T);
+
+/// `application/json` or anything ending in `+json`, parameters aside.
+fn is_json(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+    else {
+        return false;
+    };
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json"
+        || essence.starts_with("application/") && essence.ends_with("+json")
+}
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = AppError;
+
+    async fn from_request(request: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        if !is_json(request.headers()) {
+            return Err(AppError::UnsupportedMediaType(
+                "expected a Content-Type of application/json",
+            ));
+        }
+        let body =
+            Bytes::from_request(request, state)
+                .await
+                .map_err(|rejection| match rejection.status() {
+                    StatusCode::PAYLOAD_TOO_LARGE => AppError::PayloadTooLarge,
+                    _ => AppError::BadRequest(rejection.body_text()),
+                })?;
+        if body.is_empty() {
+            return Err(AppError::BadRequest("request body is empty".into()));
+        }
+        let mut deserializer = serde_json::Deserializer::from_slice(&body);
+        let value = serde_path_to_error::deserialize(&mut deserializer).map_err(unprocessable)?;
+        // anything but whitespace after the value, e.g. a second one
+        deserializer
+            .end()
+            .map_err(|err| AppError::Unprocessable(err.to_string()))?;
+        Ok(Json(value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
@@ -0,0 +1,240 @@
+//! `POST /graphql`, the recipes with just the fields and related data the
+//! client asks for.
+//!
+//! The related data is fetched per field with a [`DataLoader`], so a page of
+//! recipes asking for their ingredients is still one ingredient query, the
+//! same `any($1::int[])` batching as [`load_recipes`](crate::load_recipes).
+//! Unlike the REST endpoints the queries don't share a snapshot, each batch
+//! checks out a connection of its own.
+
+use crate::error::AppError;
+use crate::{
+    authenticate_primary, recipe_ingredients, recipe_notes, recipe_row, recipe_rows,
+    recipe_sections, recipe_steps, recipe_timeline_events, AppState, Ingredient, Note, ReadPool,
+    RecipeQuery, RecipeSummary, Section, Step, TimelineEvent,
+};
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::{
+    ComplexObject, Context, EmptyMutation, EmptySubscription, Error, ErrorExtensions, Object,
+    Result, Schema,
+};
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use async_trait::async_trait;
+use axum::extract::State;
+use axum_extra::extract::cookie::CookieJar;
+use std::collections::HashMap;
+
+pub type RecipeSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+pub fn schema() -> RecipeSchema {
+    Schema::build(Query, EmptyMutation, EmptySubscription).finish()
+}
+
+/// Unlike REST a failed field doesn't fail the whole response, so the status
+/// goes in the error's `extensions`, along with the `code` for the 401s.
+impl From<AppError> for Error {
+    fn from(err: AppError) -> Self {
+        let code = err.code();
+        let (status, message) = err.status_and_message();
+        Error::new(message).extend_with(|_, extensions| {
+            extensions.set("status", status.as_u16());
+            if let Some(code) = code {
+                extensions.set("code", code);
+            }
+        })
+    }
+}
+
+pub async fn graphql(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    request: GraphQLRequest,
+) -> Result<GraphQLResponse, AppError> {
+    // the whole request needs a session, same as REST
+    let user_id = authenticate_primary(&state, &jar).await?;
+    let viewer = Viewer {
+        user_id,
+        read_pool: state.read_pool,
+    };
+    let request = request
+        .into_inner()
+        .data(DataLoader::new(viewer, tokio::spawn));
+    Ok(state.graphql.execute(request).await.into())
+}
+
+/// Who's asking, for the ownership checks, and where to read from. One per
+/// request, which is also as long as the loaded data is kept.
+struct Viewer {
+    user_id: i32,
+    read_pool: ReadPool,
+}
+
+fn viewer<'a>(ctx: &Context<'a>) -> &'a DataLoader<Viewer> {
+    ctx.data_unchecked()
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// A recipe the user can access
+    async fn recipe(&self, ctx: &Context<'_>, id: i32) -> Result<Option<RecipeSummary>> {
+        let viewer = viewer(ctx).loader();
+        let conn = viewer.read_pool.get().await?;
+        let recipe = recipe_row(&conn, viewer.user_id, id).await?;
+        Ok(recipe.as_ref().map(RecipeSummary::from_row).transpose()?)
+    }
+
+    /// The recipes the user can access, newest first. `limit` is clamped to
+    /// between 1 and 100 and defaults to 20.
+    async fn recipes(
+        &self,
+        ctx: &Context<'_>,
+        limit: Option<i64>,
+        offset: Option<i64>,
+    ) -> Result<Vec<RecipeSummary>> {
+        let query = RecipeQuery {
+            limit,
+            offset,
+            ..Default::default()
+        };
+        query.validate()?;
+        let viewer = viewer(ctx).loader();
+        let conn = viewer.read_pool.get().await?;
+        let recipes =
+            recipe_rows(&conn, viewer.user_id, query.limit(20), query.offset(), None).await?;
+        Ok(recipes
+            .iter()
+            .map(RecipeSummary::from_row)
+            .collect::<Result<_, _>>()?)
+    }
+}
+
+#[ComplexObject]
+impl RecipeSummary {
+    async fn ingredients(&self, ctx: &Context<'_>) -> Result<Vec<Ingredient>> {
+        let ingredients = viewer(ctx).load_one(IngredientsOf(self.id)).await?;
+        Ok(ingredients.unwrap_or_default())
+    }
+
+    async fn sections(&self, ctx: &Context<'_>) -> Result<Vec<Section>> {
+        let sections = viewer(ctx).load_one(SectionsOf(self.id)).await?;
+        Ok(sections.unwrap_or_default())
+    }
+
+    async fn steps(&self, ctx: &Context<'_>) -> Result<Vec<Step>> {
+        let steps = viewer(ctx).load_one(StepsOf(self.id)).await?;
+        Ok(steps.unwrap_or_default())
+    }
+
+    /// Newest first, with their reactions
+    async fn notes(&self, ctx: &Context<'_>) -> Result<Vec<Note>> {
+        let notes = viewer(ctx).load_one(NotesOf(self.id)).await?;
+        Ok(notes.unwrap_or_default())
+    }
+
+    /// The timeline events, newest first. Unlike REST the notes aren't mixed
+    /// in, they're under `notes`.
+    async fn timeline(&self, ctx: &Context<'_>) -> Result<Vec<TimelineEvent>> {
+        let events = viewer(ctx).load_one(TimelineOf(self.id)).await?;
+        Ok(events.unwrap_or_default())
+    }
+}
+
+// One key type per relation, all of them recipe ids. The recipes were already
+// checked against the user when they were loaded, so the relations aren't.
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct IngredientsOf(i32);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct SectionsOf(i32);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct StepsOf(i32);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct NotesOf(i32);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct TimelineOf(i32);
+
+#[async_trait]
+impl Loader<IngredientsOf> for Viewer {
+    type Value = Vec<Ingredient>;
+    type Error = Error;
+
+    async fn load(&self, keys: &[IngredientsOf]) -> Result<HashMap<IngredientsOf, Self::Value>> {
+        let recipe_ids: Vec<i32> = keys.iter().map(|key| key.0).collect();
+        let conn = self.read_pool.get().await?;
+        let ingredients = recipe_ingredients(&conn, &recipe_ids).await?;
+        Ok(ingredients
+            .into_iter()
+            .map(|(recipe_id, ingredients)| (IngredientsOf(recipe_id), ingredients))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Loader<SectionsOf> for Viewer {
+    type Value = Vec<Section>;
+    type Error = Error;
+
+    async fn load(&self, keys: &[SectionsOf]) -> Result<HashMap<SectionsOf, Self::Value>> {
+        let recipe_ids: Vec<i32> = keys.iter().map(|key| key.0).collect();
+        let conn = self.read_pool.get().await?;
+        let sections = recipe_sections(&conn, &recipe_ids).await?;
+        Ok(sections
+            .into_iter()
+            .map(|(recipe_id, sections)| (SectionsOf(recipe_id), sections))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Loader<StepsOf> for Viewer {
+    type Value = Vec<Step>;
+    type Error = Error;
+
+    async fn load(&self, keys: &[StepsOf]) -> Result<HashMap<StepsOf, Self::Value>> {
+        let recipe_ids: Vec<i32> = keys.iter().map(|key| key.0).collect();
+        let conn = self.read_pool.get().await?;
+        let steps = recipe_steps(&conn, &recipe_ids).await?;
+        Ok(steps
+            .into_iter()
+            .map(|(recipe_id, steps)| (StepsOf(recipe_id), steps))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Loader<NotesOf> for Viewer {
+    type Value = Vec<Note>;
+    type Error = Error;
+
+    async fn load(&self, keys: &[NotesOf]) -> Result<HashMap<NotesOf, Self::Value>> {
+        let recipe_ids: Vec<i32> = keys.iter().map(|key| key.0).collect();
+        let conn = self.read_pool.get().await?;
+        let notes = recipe_notes(&conn, &recipe_ids, self.user_id, true, None).await?;
+        Ok(notes
+            .into_iter()
+            .map(|(recipe_id, notes)| (NotesOf(recipe_id), notes))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Loader<TimelineOf> for Viewer {
+    type Value = Vec<TimelineEvent>;
+    type Error = Error;
+
+    async fn load(&self, keys: &[TimelineOf]) -> Result<HashMap<TimelineOf, Self::Value>> {
+        let recipe_ids: Vec<i32> = keys.iter().map(|key| key.0).collect();
+        let conn = self.read_pool.get().await?;
+        let events = recipe_timeline_events(&conn, &recipe_ids, None).await?;
+        Ok(events
+            .into_iter()
+            .map(|(recipe_id, events)| (TimelineOf(recipe_id), events))
+            .collect())
+    }
+}
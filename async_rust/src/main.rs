@@ -1,31 +1,100 @@
-use axum::{extract::Extension, http::StatusCode, routing::get, Json, Router};
+use async_graphql::SimpleObject;
+use async_trait::async_trait;
+use axum::{
+    body::{BoxBody, Bytes, StreamBody},
+    extract::{DefaultBodyLimit, FromRequestParts, Path, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Router,
+};
 use axum_extra::extract::cookie::CookieJar;
-use bb8::Pool;
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::{AddrIncomingConfig, HttpConfig};
+use bb8::{Pool, PooledConnection};
 use bb8_postgres::PostgresConnectionManager;
-use chrono::Utc;
+use chrono::{TimeZone, Utc};
+use config::{Bind, Config, HttpSettings, LogFormat, PoolSettings};
+use csrf::Csrf;
+use db::{Columns, ConnectionPool, IsolationLevel, PgConnection, PgManager};
 use dotenvy::dotenv;
+use error::AppError;
+use extract::{Json, Query};
+use futures::future::BoxFuture;
+use futures::{stream, Stream, StreamExt};
 use http::Request;
+use hyper::server::accept;
 use hyper::Body;
-use serde::Serialize;
+use moka::future::Cache;
+use notify::{ChangeKind, TimelineChanges};
+use serde::{Deserialize, Deserializer, Serialize};
+use session::SessionCookies;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::any::Any;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::env;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UnixListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_postgres::Row;
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::classify::ServerErrorsFailureClass;
+use tower_http::cors::CorsLayer;
 use tower_http::trace::TraceLayer;
 use tower_request_id::{RequestId, RequestIdLayer};
-use tracing::{info, info_span, Level};
+use tracing::{field, info, info_span, Span};
+use tracing_subscriber::{filter::EnvFilter, prelude::*};
+use unicode_segmentation::UnicodeSegmentation;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
 
 use native_tls::{Certificate, TlsConnector};
 use postgres_native_tls::MakeTlsConnector;
 use std::fs;
 
+mod config;
+mod csrf;
+mod db;
+mod error;
+mod extract;
+mod graphql;
+mod notify;
+#[cfg(feature = "otel")]
+mod otel;
+mod parse;
+#[cfg(all(test, feature = "pg-tests"))]
+mod pg_tests;
+mod queries;
+mod session;
+
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    tracing_subscriber::fmt().with_max_level(Level::INFO).init();
-
-    let dsn = env::var("PG_DSN").unwrap();
+    let config = Config::from_env().unwrap_or_else(|err| {
+        eprint!("{}", err);
+        std::process::exit(1);
+    });
+    let config = Arc::new(config);
+    init_tracing(&config);
 
-    let cert = fs::read("database_cert.pem").unwrap();
+    let cert = fs::read(&config.database_cert_path).unwrap_or_else(|err| {
+        panic!(
+            "read DATABASE_CERT_PATH {}: {}",
+            config.database_cert_path.display(),
+            err
+        )
+    });
     let cert = Certificate::from_pem(&cert).unwrap();
     let connector = TlsConnector::builder()
         .add_root_certificate(cert)
@@ -33,53 +102,437 @@ async fn main() {
         .unwrap();
     let connector = MakeTlsConnector::new(connector);
 
-    let manager = PostgresConnectionManager::new_from_stringlike(dsn, connector)
-        .expect("setup conn manager, whatever that is");
-    let pool = Pool::builder()
-        .max_size(20)
-        .build(manager)
-        .await
-        .expect("created pool successfully");
+    let timeline_changes = notify::listen(config.pg_dsn.clone(), connector.clone());
+    let pool = build_pool(config.pg_dsn.clone(), connector.clone(), config.pool).await;
+    // Reads can lag behind on the replica, so a recipe or note that was just
+    // created may briefly 404 or be missing from the bundle. Sessions are
+    // always looked up on the primary so logging in works straight away.
+    //
+    // Reads go to the primary instead while the replica is down, the replica
+    // only gets PG_REPLICA_CONNECTION_TIMEOUT_MS (default 1s) to hand out a
+    // connection before that.
+    let replica = match &config.pg_replica_dsn {
+        Some(replica_dsn) => {
+            let settings = PoolSettings {
+                connection_timeout: config.replica_connection_timeout,
+                ..config.pool
+            };
+            Some(build_pool(replica_dsn.clone(), connector, settings).await)
+        }
+        None => None,
+    };
+    let read_pool = ReadPool {
+        replica,
+        primary: pool.clone(),
+    };
+    let session_cookies = SessionCookies::new(
+        config.session_cookie_format.clone(),
+        config.session_sliding,
+        pool.clone(),
+        config.session_cache_capacity,
+    );
+    let csrf_secret = match &config.secret_key {
+        Some(secret_key) => secret_key.clone().into_bytes(),
+        None => {
+            tracing::warn!("SECRET_KEY isn't set, CSRF tokens won't survive a restart");
+            rand::random::<[u8; 32]>().to_vec()
+        }
+    };
+
+    let recipe_cache: Option<RecipeCache> = config.recipe_cache_ttl.map(|ttl| {
+        Cache::builder()
+            .max_capacity(config.recipe_cache_capacity)
+            .time_to_live(ttl)
+            .support_invalidation_closures()
+            .build()
+    });
+
+    let session_cache: Option<SessionCache> = config.session_cache_ttl.map(|ttl| {
+        Cache::builder()
+            .max_capacity(config.session_cache_capacity)
+            .time_to_live(ttl)
+            .build()
+    });
+
+    let state = AppState {
+        config: config.clone(),
+        graphql: graphql::schema(),
+        pool,
+        read_pool,
+        timeline_changes,
+        recipe_cache,
+        sessions: session_cache,
+        csrf: Csrf::new(csrf_secret, session_cookies.clone()),
+        session_cookies,
+    };
+    let app = app(routes(), state);
+
+    let HttpSettings {
+        http1_keepalive,
+        tcp_keepalive,
+        http2_keepalive,
+        http2_max_concurrent_streams,
+        http2_only,
+    } = config.http;
+
+    let addr = match &config.bind {
+        Bind::Unix(path) => {
+            // left over from a crash, binding fails while it's there
+            if fs::metadata(path).is_ok() {
+                fs::remove_file(path).expect("remove stale unix socket");
+            }
+            let listener = UnixListener::bind(path).expect("bind unix socket");
+            let incoming = accept::poll_fn(move |cx| {
+                listener
+                    .poll_accept(cx)
+                    .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+            });
+
+            tracing::info!("listening on unix:{}", path.display());
+            axum::Server::builder(incoming)
+                .http2_only(http2_only)
+                .http1_keepalive(http1_keepalive)
+                .http2_keep_alive_interval(http2_keepalive)
+                .http2_max_concurrent_streams(http2_max_concurrent_streams)
+                .serve(app.into_make_service())
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .unwrap();
+            fs::remove_file(path).expect("remove unix socket");
+            flush_traces().await;
+            return;
+        }
+        Bind::Tcp(addr) => *addr,
+    };
+    let listener = tcp_listener(addr);
+
+    match &config.tls {
+        Some(tls) => {
+            let tls_config = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+                .await
+                .expect("load TLS cert and key");
+
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown_signal().await;
+                    handle.graceful_shutdown(None);
+                }
+            });
 
-    let app = Router::new()
+            tracing::info!("listening on {} with TLS", addr);
+            axum_server::from_tcp_rustls(listener, tls_config)
+                .handle(handle)
+                .http_config(
+                    HttpConfig::new()
+                        .http1_keep_alive(http1_keepalive)
+                        .http2_keep_alive_interval(http2_keepalive)
+                        .http2_max_concurrent_streams(http2_max_concurrent_streams)
+                        .build(),
+                )
+                .addr_incoming_config(
+                    AddrIncomingConfig::new()
+                        .tcp_keepalive(tcp_keepalive)
+                        .build(),
+                )
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
+        None => {
+            tracing::info!("listening on {}", addr);
+            axum::Server::from_tcp(listener)
+                .expect("listen on the TCP socket")
+                .http2_only(http2_only)
+                .http1_keepalive(http1_keepalive)
+                .tcp_keepalive(tcp_keepalive)
+                .http2_keep_alive_interval(http2_keepalive)
+                .http2_max_concurrent_streams(http2_max_concurrent_streams)
+                .serve(app.into_make_service())
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .unwrap();
+        }
+    }
+    flush_traces().await;
+}
+
+/// Every route, without the middleware, see [`app`].
+fn routes() -> Router<AppState> {
+    Router::new()
         .route("/api/v1/recipes", get(recipes_list))
+        .route("/api/v1/recipes/random", get(random_recipe))
+        .route(
+            "/api/v1/recipes/batch",
+            get(recipes_batch).post(recipes_batch_post),
+        )
+        .route("/api/v1/tags", get(tags_list))
+        .route("/api/v1/csrf", get(csrf_token))
+        .route(
+            "/api/v1/recipes/:id",
+            get(recipe_detail)
+                .patch(update_recipe)
+                .delete(delete_recipe),
+        )
+        .route(
+            "/api/v1/recipes/:id/archive",
+            post(archive_recipe).patch(update_archived),
+        )
+        .route("/api/v1/recipes/:id/unarchive", post(unarchive_recipe))
+        .route(
+            "/api/v1/recipes/:id/ingredients",
+            get(recipe_ingredients_list),
+        )
+        .route(
+            "/api/v1/recipes/:id/notes",
+            get(recipe_notes_list).post(create_note),
+        )
+        .route("/api/v1/recipes/:id/timeline", get(recipe_timeline))
+        .route("/api/v1/recipes/:id/timeline/stream", get(timeline_stream))
+        .route(
+            "/api/v1/notes/:id/reactions",
+            post(add_reaction).delete(remove_reaction),
+        )
+        .route("/api/v1/notes/:id/react", post(toggle_reaction))
+        .route("/graphql", post(graphql::graphql))
+        .route("/version", get(version))
+        .route("/debug/pool", get(debug_pool))
+        // also redirects `/docs` to `/docs/`
+        .merge(SwaggerUi::new("/docs").url("/api-docs/openapi.json", ApiDoc::openapi()))
+        .fallback(not_found)
+}
+
+/// `routes` behind the middleware, ready to serve.
+fn app(routes: Router<AppState>, state: AppState) -> Router {
+    let cors = state.config.cors_allowed_origins.clone().map(|origins| {
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
+            .allow_headers([header::CONTENT_TYPE, csrf::X_CSRF_TOKEN.clone()])
+            .allow_credentials(true)
+    });
+
+    let app = routes
+        .layer(middleware::from_fn_with_state(
+            state.csrf.clone(),
+            csrf::protect,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.session_cookies.clone(),
+            session::slide,
+        ))
+        .layer(middleware::from_fn(private_cache))
+        .layer(middleware::from_fn_with_state(
+            state.config.retry_after,
+            error::retry_after,
+        ))
+        // only the extractors that read the body check it, so the GETs don't
+        // pay for it
+        .layer(DefaultBodyLimit::max(state.config.max_body_bytes))
+        .with_state(state);
+    let app = match cors {
+        Some(cors) => app.layer(cors),
+        None => app,
+    };
+    app
+        // inside the trace layer so the panic is logged with the request's span
+        // and the 500 shows up in `on_response`
+        .layer(CatchPanicLayer::custom(handle_panic))
         .layer(
-            TraceLayer::new_for_http().make_span_with(|request: &Request<Body>| {
-                // taken from: https://github.com/imbolc/tower-request-id/blob/1171b95f15ba5a3456b0425cbc0c4d486444ceaf/examples/logging.rs
-                let request_id = request
-                    .extensions()
-                    .get::<RequestId>()
-                    .map(ToString::to_string)
-                    .unwrap_or_else(|| "unknown".into());
-                // HACK: get some logging, not sure how to get spans to show up
-                info!(
-                    "request {id} {method} {uri}",
-                    id = request_id,
-                    method = request.method(),
-                    uri = request.uri(),
-                );
-                info_span!(
-                    "request",
-                    id = %request_id,
-                    method = %request.method(),
-                    uri = %request.uri(),
+            TraceLayer::new_for_http()
+                .make_span_with(|request: &Request<Body>| {
+                    // taken from: https://github.com/imbolc/tower-request-id/blob/1171b95f15ba5a3456b0425cbc0c4d486444ceaf/examples/logging.rs
+                    let request_id = request
+                        .extensions()
+                        .get::<RequestId>()
+                        .map(ToString::to_string)
+                        .unwrap_or_else(|| "unknown".into());
+                    // the remaining fields are filled in by the handler and
+                    // `on_response` once we know them
+                    let span = info_span!(
+                        "request",
+                        request_id = %request_id,
+                        method = %request.method(),
+                        uri = %request.uri(),
+                        user_id = field::Empty,
+                        recipe_id = field::Empty,
+                        status = field::Empty,
+                        latency_ms = field::Empty,
+                    );
+                    #[cfg(feature = "otel")]
+                    otel::set_parent(&span, request.headers());
+                    span
+                })
+                .on_response(
+                    |response: &Response<BoxBody>, latency: Duration, span: &Span| {
+                        span.record("status", response.status().as_u16());
+                        span.record("latency_ms", latency.as_millis() as u64);
+                        // the access log line, all the interesting bits are
+                        // fields on the span
+                        info!("finished request");
+                    },
                 )
-            }),
+                .on_failure(
+                    |error: ServerErrorsFailureClass, latency: Duration, _span: &Span| {
+                        tracing::error!(
+                            %error,
+                            latency_ms = latency.as_millis() as u64,
+                            "request failed"
+                        );
+                    },
+                ),
         )
         .layer(RequestIdLayer)
-        .layer(Extension(pool));
+}
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
-    tracing::info!("listening on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+/// Bind `addr`, and for the IPv6 wildcard `[::]` accept IPv4 clients on the
+/// same socket too rather than leaving that to the `net.ipv6.bindv6only`
+/// sysctl.
+fn tcp_listener(addr: SocketAddr) -> std::net::TcpListener {
+    let listener = if addr.is_ipv6() && addr.ip().is_unspecified() {
+        let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))
+            .expect("create TCP socket");
+        socket.set_only_v6(false).expect("enable dual-stack");
+        // same as what std does before binding
+        socket.set_reuse_address(true).expect("set SO_REUSEADDR");
+        socket.bind(&addr.into()).expect("bind TCP socket");
+        socket.listen(1024).expect("listen on TCP socket");
+        socket.into()
+    } else {
+        std::net::TcpListener::bind(addr).expect("bind TCP socket")
+    };
+    // tokio takes the socket over as it is
+    listener
+        .set_nonblocking(true)
+        .expect("make the TCP socket non-blocking");
+    listener
+}
+
+/// Send the spans still waiting to be exported, a no-op without the `otel`
+/// feature.
+async fn flush_traces() {
+    #[cfg(feature = "otel")]
+    otel::shutdown().await;
+}
+
+/// Resolves on Ctrl-C or SIGTERM, after which in-flight requests are allowed to
+/// finish but no new connections are accepted.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("install Ctrl-C handler");
+    };
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!("shutting down");
+}
+
+#[cfg(all(feature = "console", not(tokio_unstable)))]
+compile_error!("the console feature needs RUSTFLAGS=\"--cfg tokio_unstable\"");
+
+/// Log to stdout as `LOG_FORMAT` and filtered by `RUST_LOG` or `LOG_LEVEL`,
+/// and with the `console` feature and `TOKIO_CONSOLE=1` serve the runtime's
+/// task data to `tokio-console` too. With the `otel` feature and `OTEL_EXPORTER_OTLP_ENDPOINT` the spans are exported as well.
+fn init_tracing(config: &Config) {
+    let registry = tracing_subscriber::registry();
+    #[cfg(feature = "console")]
+    let registry = registry.with(config.tokio_console.then(console_subscriber::spawn));
+    #[cfg(feature = "otel")]
+    let registry = registry.with(config.otlp_endpoint.as_deref().map(otel::layer));
+    // checked when the config was read
+    let filter = EnvFilter::new(&config.log_filter);
+    let fmt = match config.log_format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer().with_filter(filter).boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_filter(filter)
+            .boxed(),
+    };
+    registry.with(fmt).init();
+    #[cfg(not(feature = "console"))]
+    if config.tokio_console {
+        tracing::warn!("TOKIO_CONSOLE=1 needs a build with the console feature");
+    }
+    #[cfg(not(feature = "otel"))]
+    if config.otlp_endpoint.is_some() {
+        tracing::warn!("OTEL_EXPORTER_OTLP_ENDPOINT needs a build with the otel feature");
+    }
+}
+
+/// Everything the handlers share.
+#[derive(Clone)]
+struct AppState {
+    config: Arc<Config>,
+    graphql: graphql::RecipeSchema,
+    pool: ConnectionPool,
+    read_pool: ReadPool,
+    timeline_changes: TimelineChanges,
+    recipe_cache: Option<RecipeCache>,
+    sessions: Option<SessionCache>,
+    session_cookies: SessionCookies,
+    csrf: Csrf,
+}
+
+/// Where the read-only queries go, the replica if there is one and otherwise
+/// the primary.
+#[derive(Clone)]
+struct ReadPool {
+    replica: Option<ConnectionPool>,
+    primary: ConnectionPool,
+}
+
+impl ReadPool {
+    /// Falls back to the primary while the replica can't hand out a
+    /// connection, e.g. it's down or the pool is exhausted.
+    async fn get(&self) -> Result<PooledConnection<'_, PgManager>, AppError> {
+        if let Some(replica) = &self.replica {
+            match replica.get().await {
+                Ok(conn) => return Ok(conn),
+                Err(err) => tracing::warn!(%err, "replica unavailable, reading from the primary"),
+            }
+        }
+        get_conn(&self.primary).await
+    }
+}
+
+/// Keyed by `(user_id, recipe_id)` since parts of the bundle, like
+/// [`ReactionSummary::reacted`], depend on who's asking.
+type RecipeCache = Cache<(i32, i32), Arc<Recipe>>;
+
+/// Drop everyone's cached bundle of the recipe after a write. The ETag check
+/// would turn them away anyway, this just doesn't keep them until the TTL.
+fn invalidate_recipe(cache: Option<&RecipeCache>, recipe_id: i32) {
+    if let Some(cache) = cache {
+        cache
+            .invalidate_entries_if(move |&(_, id), _| id == recipe_id)
+            .expect("the cache supports invalidation closures");
+    }
 }
 
-type ConnectionPool = Pool<PostgresConnectionManager<MakeTlsConnector>>;
+/// Session key to user id. Entries aren't invalidated, so a session that's
+/// logged out keeps working for up to the TTL. One that expires stops
+/// working on time, see [`CachedSession::expire_date`].
+type SessionCache = Cache<String, CachedSession>;
 
-#[derive(Serialize, Default)]
+#[derive(Clone)]
+struct CachedSession {
+    user_id: i32,
+    /// The session's own expiry, past it the entry is ignored and the lookup
+    /// tells the user it's expired
+    expire_date: chrono::DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema, SimpleObject, Clone, Default)]
 struct Ingredient {
     id: i32,
     position: String,
@@ -88,21 +541,55 @@ struct Ingredient {
     description: String,
 }
 
-#[derive(Serialize, Default)]
+impl Ingredient {
+    fn from_row(row: &Row) -> Result<Self, AppError> {
+        Ok(Ingredient {
+            id: row.column("id")?,
+            position: row.column("position")?,
+            quantity: row.column("quantity")?,
+            name: row.column("name")?,
+            description: row.column("description")?,
+        })
+    }
+}
+
+#[derive(Serialize, ToSchema, SimpleObject, Clone, Default)]
 struct Step {
     id: i32,
     position: String,
     text: String,
 }
 
-#[derive(Serialize, Clone, Default, Debug)]
+impl Step {
+    fn from_row(row: &Row) -> Result<Self, AppError> {
+        Ok(Step {
+            id: row.column("id")?,
+            position: row.column("position")?,
+            text: row.column("text")?,
+        })
+    }
+}
+
+/// Reactions are hard deleted, removing one deletes the row, so unlike the
+/// other related data the reaction queries don't filter on `deleted_at`.
+#[derive(Serialize, ToSchema, SimpleObject, Clone, Default, Debug)]
 struct Reaction {
     id: i32,
     emoji: String,
     created_by_id: i32,
 }
 
-#[derive(Serialize, Default)]
+impl Reaction {
+    fn from_row(row: &Row) -> Result<Self, AppError> {
+        Ok(Reaction {
+            id: row.column("id")?,
+            emoji: row.column("emoji")?,
+            created_by_id: row.column("created_by_id")?,
+        })
+    }
+}
+
+#[derive(Serialize, ToSchema, SimpleObject, Clone, Default)]
 struct Note {
     id: i32,
     text: String,
@@ -110,365 +597,3152 @@ struct Note {
     name: Option<String>,
     modified_at: chrono::DateTime<Utc>,
     created_at: chrono::DateTime<Utc>,
-    reactions: Vec<Reaction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reactions: Option<Vec<Reaction>>,
+    /// The `reactions` counted by emoji. Only emoji someone has actually
+    /// reacted with are listed, there are no zero counts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reaction_summary: Option<Vec<ReactionSummary>>,
+}
+
+impl Note {
+    /// Without the reactions, they're a query of their own.
+    fn from_row(row: &Row) -> Result<Self, AppError> {
+        Ok(Note {
+            id: row.column("id")?,
+            text: row.column("text")?,
+            email: row.column("email")?,
+            name: row.column("name")?,
+            modified_at: row.column("modified")?,
+            created_at: row.column("created")?,
+            reactions: None,
+            reaction_summary: None,
+        })
+    }
+}
+
+/// How many of the user's recipes have a tag.
+#[derive(Serialize, ToSchema)]
+struct TagCount {
+    tag: String,
+    count: i64,
+}
+
+/// The reactions on a note grouped by emoji, in the order each emoji was first
+/// used so the UI doesn't reshuffle as counts change.
+#[derive(Serialize, ToSchema, SimpleObject, Clone, Debug)]
+struct ReactionSummary {
+    emoji: String,
+    count: i64,
+    /// Whether the current user is one of the reactors
+    reacted: bool,
+}
+
+/// `reactions` should be newest first, like the reaction query returns them.
+fn summarize_reactions(reactions: &[Reaction], user_id: i32) -> Vec<ReactionSummary> {
+    let mut summary: Vec<ReactionSummary> = vec![];
+    for reaction in reactions.iter().rev() {
+        let reacted = reaction.created_by_id == user_id;
+        match summary.iter_mut().find(|s| s.emoji == reaction.emoji) {
+            Some(existing) => {
+                existing.count += 1;
+                existing.reacted |= reacted;
+            }
+            None => summary.push(ReactionSummary {
+                emoji: reaction.emoji.clone(),
+                count: 1,
+                reacted,
+            }),
+        }
+    }
+    summary
 }
 
-#[derive(Serialize, Default)]
+#[derive(Serialize, ToSchema, SimpleObject, Clone, Default)]
 struct Section {
     id: i32,
     title: String,
     position: String,
 }
 
-#[derive(Serialize, Default)]
+impl Section {
+    fn from_row(row: &Row) -> Result<Self, AppError> {
+        Ok(Section {
+            id: row.column("id")?,
+            title: row.column("title")?,
+            position: row.column("position")?,
+        })
+    }
+}
+
+#[derive(Serialize, ToSchema, SimpleObject, Clone, Default)]
 struct TimelineEvent {
     id: i32,
     action: String,
     created_at: chrono::DateTime<Utc>,
     created_by_id: Option<i32>,
+    /// The user's name, or their email if they haven't set one
     created_by_name: Option<String>,
 }
 
-#[derive(Serialize)]
+impl TimelineEvent {
+    fn from_row(row: &Row) -> Result<Self, AppError> {
+        Ok(TimelineEvent {
+            id: row.column("id")?,
+            action: row.column("action")?,
+            created_at: row.column("created")?,
+            created_by_id: row.column("created_by_id")?,
+            created_by_name: row.column("created_by_name")?,
+        })
+    }
+}
+
+/// What happened, stored as the event's `action`. The seed data's events
+/// predate these and say e.g. `created`.
+#[derive(Clone, Copy, Debug)]
+enum TimelineAction {
+    NoteCreated,
+    Edited,
+    Archived,
+    Unarchived,
+}
+
+impl TimelineAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            TimelineAction::NoteCreated => "note_created",
+            TimelineAction::Edited => "edited",
+            TimelineAction::Archived => "archived",
+            TimelineAction::Unarchived => "unarchived",
+        }
+    }
+}
+
+/// Externally tagged, e.g. `{"Section": {...}}`, so the variant name is the
+/// discriminator.
+#[derive(Serialize, ToSchema)]
 enum IngredientLike {
     Ingredient(Ingredient),
     Section(Section),
 }
 
-#[derive(Serialize)]
+impl IngredientLike {
+    fn position(&self) -> &str {
+        match self {
+            IngredientLike::Ingredient(ingredient) => &ingredient.position,
+            IngredientLike::Section(section) => &section.position,
+        }
+    }
+}
+
+/// Who a recipe belongs to. Externally tagged, same as [`IngredientLike`].
+#[derive(Serialize, ToSchema)]
+enum Owner {
+    User {
+        id: i32,
+        /// The user's name, or their email if they haven't set one
+        name: String,
+    },
+    Team {
+        id: i32,
+        name: String,
+    },
+}
+
+impl Owner {
+    /// From the recipe query's joins, `None` if neither matched which the
+    /// schema shouldn't allow.
+    fn from_row(recipe: &Row) -> Result<Option<Self>, AppError> {
+        if let Some(id) = recipe.column("user_id")? {
+            return Ok(Some(Owner::User {
+                id,
+                name: recipe.column("user_name")?,
+            }));
+        }
+        if let Some(id) = recipe.column("team_id")? {
+            return Ok(Some(Owner::Team {
+                id,
+                name: recipe.column("team_name")?,
+            }));
+        }
+        Ok(None)
+    }
+}
+
+/// Externally tagged, same as [`IngredientLike`].
+#[derive(Serialize, ToSchema)]
 enum TimelineLike {
     TimelineEvent(TimelineEvent),
     Note(Note),
 }
 
-#[derive(Serialize, Default)]
+impl TimelineLike {
+    /// What the timeline is ordered by, newest last: `created`, then events
+    /// before notes, then the id. The queries page by the same key, see
+    /// [`TimelineCursor`].
+    fn key(&self) -> (chrono::DateTime<Utc>, i32, i32) {
+        match self {
+            TimelineLike::TimelineEvent(event) => {
+                (event.created_at, TimelineCursor::EVENT, event.id)
+            }
+            TimelineLike::Note(note) => (note.created_at, TimelineCursor::NOTE, note.id),
+        }
+    }
+}
+
+/// A [`RecipeSummary`] with its owner and the related data, the summary's
+/// fields at the top level.
+#[derive(Serialize, ToSchema, Default)]
 struct Recipe {
+    #[serde(flatten)]
+    summary: RecipeSummary,
+    owner: Option<Owner>,
+    /// Left out rather than empty when excluded with `?include=`, same for
+    /// the other related data.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ingredients: Option<Vec<IngredientLike>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    steps: Option<Vec<Step>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeline: Option<Vec<TimelineLike>>,
+    /// Set when loaded through the detail endpoint so cached entries can be
+    /// checked against the current version.
+    #[serde(skip)]
+    etag: Option<String>,
+}
+
+/// The recipe row on its own, without any of the related data.
+///
+/// Also the GraphQL `Recipe`, the related data has resolvers of its own in
+/// [`graphql`].
+#[derive(Serialize, ToSchema, SimpleObject, Default)]
+#[graphql(name = "Recipe", complex)]
+struct RecipeSummary {
     id: i32,
     name: String,
     author: Option<String>,
     source: Option<String>,
     time: String,
+    /// Parsed from `time`, unset when it doesn't look like a duration
+    time_minutes: Option<i32>,
     servings: String,
+    /// Parsed from `servings`, the same as `servings_max` unless it's a range
+    servings_min: Option<i32>,
+    servings_max: Option<i32>,
     tags: Vec<String>,
     archived_at: Option<chrono::DateTime<Utc>>,
-    created_at: Option<chrono::DateTime<Utc>>,
-    ingredients: Vec<IngredientLike>,
-    steps: Vec<Step>,
-    timeline: Vec<TimelineLike>,
+    created_at: chrono::DateTime<Utc>,
+}
+
+impl RecipeSummary {
+    fn from_row(recipe: &Row) -> Result<Self, AppError> {
+        let time: String = recipe.column("time")?;
+        let servings: String = recipe.column("servings")?;
+        let (servings_min, servings_max) = parse::servings(&servings).unzip();
+        Ok(RecipeSummary {
+            id: recipe.column("id")?,
+            name: recipe.column("name")?,
+            author: recipe.column("author")?,
+            source: recipe.column("source")?,
+            time_minutes: parse::minutes(&time),
+            time,
+            servings_min,
+            servings_max,
+            servings,
+            tags: recipe.column("tags")?,
+            archived_at: recipe.column("archived_at")?,
+            created_at: recipe.column("created")?,
+        })
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        recipes_list,
+        random_recipe,
+        recipes_batch,
+        recipes_batch_post,
+        tags_list,
+        recipe_detail,
+        update_recipe,
+        delete_recipe,
+        archive_recipe,
+        unarchive_recipe,
+        update_archived,
+        recipe_ingredients_list,
+        recipe_notes_list,
+        create_note,
+        recipe_timeline,
+        timeline_stream,
+        add_reaction,
+        remove_reaction,
+        toggle_reaction,
+        csrf_token,
+        version
+    ),
+    components(schemas(
+        Recipe,
+        RecipeSummary,
+        Owner,
+        IngredientLike,
+        Ingredient,
+        Section,
+        Step,
+        TimelineLike,
+        TimelineEvent,
+        Note,
+        Reaction,
+        ReactionSummary,
+        TagCount,
+        BatchRequest,
+        UpdateRecipe,
+        ArchiveRecipe,
+        CreateNote,
+        ReactionPayload,
+        CsrfToken,
+        Version
+    )),
+    modifiers(&SessionCookie)
+)]
+struct ApiDoc;
+
+struct SessionCookie;
+
+impl Modify for SessionCookie {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "session",
+                SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("sessionid"))),
+            );
+        }
+    }
+}
+
+/// Where a page of recipes ended, as the `(created, id)` of its last recipe.
+///
+/// Unlike an offset this doesn't skip or repeat recipes when others are added
+/// or deleted between pages.
+struct Cursor {
+    created: chrono::DateTime<Utc>,
+    id: i32,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let cursor = format!("{}:{}", self.created.timestamp_micros(), self.id);
+        base64::encode_config(cursor, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let cursor = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?;
+        let (micros, id) = std::str::from_utf8(&cursor).ok()?.split_once(':')?;
+        Some(Cursor {
+            created: from_micros(micros.parse().ok()?)?,
+            id: id.parse().ok()?,
+        })
+    }
+}
+
+/// The inverse of `timestamp_micros`, `None` if it's out of range.
+fn from_micros(micros: i64) -> Option<chrono::DateTime<Utc>> {
+    Utc.timestamp_opt(
+        micros.div_euclid(1_000_000),
+        (micros.rem_euclid(1_000_000) * 1_000) as u32,
+    )
+    .single()
+}
+
+/// Everything `recipes_list` takes, parsed and checked in one place so a bad
+/// parameter is always a 422 naming it.
+#[derive(Deserialize, IntoParams, Default)]
+struct RecipeQuery {
+    /// Page size, clamped to between 1 and 100
+    limit: Option<i64>,
+    /// Number of items to skip, 0 by default
+    offset: Option<i64>,
+    /// The `next_cursor` from the previous page, can't be combined with
+    /// `offset`
+    after: Option<String>,
+    /// Wrap the recipes in an [`Envelope`], always a list even with `limit=1`
+    envelope: Option<bool>,
+    /// Set to `false` to skip counting the recipes for `X-Total-Count`
+    count: Option<bool>,
+    /// Comma separated list of `ingredients`, `steps`, `timeline`, `notes`
+    /// and `reactions` to return, just the ingredients and steps by default
+    include: Option<String>,
+    /// Comma separated list of the top level recipe fields to return, e.g.
+    /// `id,name,tags`, everything by default
+    fields: Option<String>,
+    /// Serialize the list a recipe at a time as it's sent instead of
+    /// buffering the whole body, has no effect with `envelope` or `limit=1`
+    stream: Option<bool>,
+}
+
+impl RecipeQuery {
+    const MAX_LIMIT: i64 = 100;
+
+    fn validate(&self) -> Result<(), AppError> {
+        if self.offset.is_some_and(|offset| offset < 0) {
+            return Err(AppError::Unprocessable("offset: can't be negative".into()));
+        }
+        if self.offset.is_some() && self.after.is_some() {
+            return Err(AppError::Unprocessable(
+                "after: can't be combined with offset".into(),
+            ));
+        }
+        self.cursor()?;
+        self.include()?;
+        self.fields()?;
+        Ok(())
+    }
+
+    fn cursor(&self) -> Result<Option<Cursor>, AppError> {
+        self.after
+            .as_deref()
+            .map(|after| {
+                Cursor::decode(after).ok_or(AppError::Unprocessable("after: invalid cursor".into()))
+            })
+            .transpose()
+    }
+
+    fn limit(&self, default: i64) -> i64 {
+        self.limit.unwrap_or(default).clamp(1, Self::MAX_LIMIT)
+    }
+
+    fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0)
+    }
+
+    fn include(&self) -> Result<Include, AppError> {
+        match self.include.as_deref() {
+            Some(include) => Include::parse(include),
+            None => Ok(Include::LIST),
+        }
+    }
+
+    fn fields(&self) -> Result<Option<Vec<&'static str>>, AppError> {
+        self.fields.as_deref().map(parse_fields).transpose()
+    }
+}
+
+/// The top level fields `?fields=` picks from, the related data is picked
+/// with `?include=` instead.
+const RECIPE_FIELDS: [&str; 13] = [
+    "id",
+    "name",
+    "author",
+    "source",
+    "time",
+    "time_minutes",
+    "servings",
+    "servings_min",
+    "servings_max",
+    "tags",
+    "archived_at",
+    "created_at",
+    "owner",
+];
+
+fn parse_fields(fields: &str) -> Result<Vec<&'static str>, AppError> {
+    fields
+        .split(',')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .map(|field| {
+            RECIPE_FIELDS
+                .iter()
+                .find(|known| **known == field)
+                .copied()
+                .ok_or(AppError::Unprocessable(format!(
+                    "fields: unknown field {}",
+                    field
+                )))
+        })
+        .collect()
+}
+
+/// Drop the top level fields that weren't asked for, the related data is
+/// already left out by `Include`.
+fn select_fields(recipe: &Recipe, fields: &[&str]) -> serde_json::Value {
+    let mut value = serde_json::to_value(recipe).expect("serializing a recipe can't fail");
+    if let Some(object) = value.as_object_mut() {
+        object.retain(|key, _| {
+            !RECIPE_FIELDS.contains(&key.as_str()) || fields.contains(&key.as_str())
+        });
+    }
+    value
+}
+
+/// Shape the response for `recipes_list`, see its docs.
+fn recipes_response<T: Serialize + Send + 'static>(
+    mut recipes: Vec<T>,
+    limit: i64,
+    meta: Option<Meta>,
+    stream: bool,
+) -> Result<Response, AppError> {
+    if let Some(meta) = meta {
+        return Ok(Json(Envelope {
+            data: recipes,
+            meta,
+        })
+        .into_response());
+    }
+    if limit == 1 {
+        let recipe = recipes
+            .pop()
+            .ok_or(AppError::NotFound("no recipes found"))?;
+        return Ok(Json(recipe).into_response());
+    }
+    if stream {
+        return Ok(json_array_stream(recipes));
+    }
+    Ok(Json(recipes).into_response())
+}
+
+/// A JSON array body that serializes each element as it's sent.
+///
+/// The status and headers are out by then, so if an element fails to
+/// serialize the body is cut off, the client gets invalid JSON rather than a
+/// list that looks complete.
+fn json_array_stream<T: Serialize + Send + 'static>(items: Vec<T>) -> Response {
+    let elements = stream::iter(items.into_iter().enumerate()).map(|(i, item)| {
+        let mut chunk = if i == 0 { vec![] } else { vec![b','] };
+        serde_json::to_writer(&mut chunk, &item).map_err(|err| {
+            tracing::error!(%err, "serializing streamed response failed, truncating");
+            err
+        })?;
+        Ok::<_, serde_json::Error>(Bytes::from(chunk))
+    });
+    let body = stream::once(async { Ok(Bytes::from_static(b"[")) })
+        .chain(elements)
+        .chain(stream::once(async { Ok(Bytes::from_static(b"]")) }));
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        StreamBody::new(body),
+    )
+        .into_response()
+}
+
+/// A page of results along with some metadata about the request.
+///
+/// Not in the OpenAPI schema, utoipa can't alias `Envelope<Vec<Recipe>>`.
+#[derive(Serialize)]
+struct Envelope<T> {
+    data: T,
+    meta: Meta,
+}
+
+#[derive(Serialize)]
+struct Meta {
+    /// Same as in the server logs, handy for bug reports
+    request_id: String,
+    /// Pass as `after` to get the next page, if there is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+    /// Number of items in `data`
+    count: usize,
+}
+
+/// The id [`RequestIdLayer`] assigned to the request.
+struct CurrentRequestId(String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CurrentRequestId {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(CurrentRequestId(
+            parts
+                .extensions
+                .get::<RequestId>()
+                .map(ToString::to_string)
+                .unwrap_or_default(),
+        ))
+    }
 }
 
-// basic handler that responds with a static string
+/// List recipes
+///
+/// The newest recipes the user can access. Returns a single recipe by default, or a list of them when `limit` is more
+/// than 1. With `envelope=true` the list is always returned, wrapped as
+/// `{"data": [...], "meta": {"request_id": ..., "count": ...}}`.
+///
+/// `include` and `fields` trim the recipes down, e.g.
+/// `?fields=id,name,tags&include=` for just the headers. The related data
+/// that isn't included is never queried, and unless asked for that's the
+/// timeline, notes and reactions.
+#[utoipa::path(
+    get,
+    path = "/api/v1/recipes",
+    params(RecipeQuery),
+    responses(
+        (status = 200, description = "The newest recipe", body = Recipe,
+            headers(("x-total-count" = i64, description = "How many recipes the user can access, unless `count=false`"))),
+        (status = 422, description = "A parameter that doesn't parse, a negative `offset`, an invalid `after` cursor, both of them, or an unknown `include` or `field`"),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 404, description = "Only with `limit=1` and no `envelope`, the user has no recipes"),
+    ),
+    security(("session" = []))
+)]
 async fn recipes_list(
-    Extension(pool): Extension<ConnectionPool>,
+    State(state): State<AppState>,
+    Query(params): Query<RecipeQuery>,
+    CurrentRequestId(request_id): CurrentRequestId,
     jar: CookieJar,
-) -> Result<Json<Recipe>, (StatusCode, String)> {
-    let session_id = jar
-        .get("sessionid")
-        .map(|cookie| cookie.value().to_owned())
-        .ok_or((StatusCode::UNAUTHORIZED, "problem parsing session".into()))?;
+) -> Result<Response, AppError> {
+    params.validate()?;
+    let include = params.include()?;
+    let fields = params.fields()?;
+    let user_id = authenticate_primary(&state, &jar).await?;
 
-    tracing::debug!("getting conn...");
+    // all the queries see the same snapshot, so a write landing halfway
+    // through can't leave us with e.g. a note but not its reactions
+    let conn = state.read_pool.get().await?;
+    in_transaction(&conn, IsolationLevel::RepeatableRead, move |conn| {
+        Box::pin(async move {
+            let limit = params.limit(1);
+            let offset = params.offset();
+            let cursor = params.cursor()?;
 
-    let conn = pool
-        .get()
-        .await
-        .map_err(|_err| (StatusCode::INTERNAL_SERVER_ERROR, "foo".into()))?;
+            let recipes = recipe_rows(conn, user_id, limit, offset, cursor.as_ref()).await?;
 
-    tracing::debug!("conn done");
-    conn.execute("SET TIME ZONE 'UTC'", &[])
-        .await
-        .map_err(internal_error)?;
+            // same predicate as `recipe_rows`, without the order and limit
+            let total: Option<i64> = if params.count.unwrap_or(true) {
+                let row = conn
+                    .query_one(queries::RECIPE_COUNT, &[&user_id])
+                    .timed("recipe_count")
+                    .await?;
+                Some(row.column("count")?)
+            } else {
+                None
+            };
 
-    let now_utc = Utc::now();
-    tracing::debug!("conn done");
+            // a short page means there's nothing after it
+            let next_cursor = recipes
+                .last()
+                .filter(|_| recipes.len() as i64 == limit)
+                .map(|last| -> Result<_, AppError> {
+                    Ok(Cursor {
+                        created: last.column("created")?,
+                        id: last.column("id")?,
+                    }
+                    .encode())
+                })
+                .transpose()?;
 
-    let maybe_session = conn
-        .query_one(
-            r#"
-SELECT
-	"user_sessions_session"."user_id"
-FROM
-	"user_sessions_session"
-WHERE ("user_sessions_session"."expire_date" > $2::timestamptz
-	AND "user_sessions_session"."session_key" = $1
-    )
-LIMIT 1;"#,
-            // hit    |                            ^^^^^^^ expected `&dyn ToSql + Sync`, found struct `chrono::DateTime<Utc>`
-            // needed to add features = ["with-chrono-0_4"]
-            &[&session_id, &now_utc],
-        )
-        .await
-        .map_err(internal_error)?;
+            let recipes = load_recipes(conn, &recipes, user_id, include).await?;
+            let meta = (params.envelope == Some(true)).then(|| Meta {
+                request_id,
+                next_cursor: next_cursor.clone(),
+                count: recipes.len(),
+            });
+            let stream = params.stream == Some(true);
+            let mut response = match &fields {
+                Some(fields) => recipes_response(
+                    recipes
+                        .iter()
+                        .map(|recipe| select_fields(recipe, fields))
+                        .collect(),
+                    limit,
+                    meta,
+                    stream,
+                )?,
+                None => recipes_response(recipes, limit, meta, stream)?,
+            };
+            if let Some(next_cursor) = next_cursor {
+                let link = format!(
+                    r#"</api/v1/recipes?limit={}&after={}>; rel="next""#,
+                    limit, next_cursor
+                );
+                response.headers_mut().insert(
+                    header::LINK,
+                    HeaderValue::from_str(&link).expect("cursor is url safe"),
+                );
+            }
+            if let Some(total) = total {
+                response
+                    .headers_mut()
+                    .insert("x-total-count", HeaderValue::from(total));
+            }
+            Ok(response)
+        })
+    })
+    .await
+}
 
-    let user_id: i32 = maybe_session
-        .try_get("user_id")
-        .map_err(|_err| (StatusCode::UNAUTHORIZED, "unauthorized".into()))?;
+/// Get a random recipe
+///
+/// Picks one of the recipes the user can access, for "surprise me".
+#[utoipa::path(
+    get,
+    path = "/api/v1/recipes/random",
+    responses(
+        (status = 200, description = "A random recipe", body = Recipe),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 404, description = "The user doesn't have any recipes"),
+    ),
+    security(("session" = []))
+)]
+async fn random_recipe(
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> Result<Json<Recipe>, AppError> {
+    let user_id = authenticate_primary(&state, &jar).await?;
 
-    let limit: i64 = 1;
+    let conn = state.read_pool.get().await?;
+    in_transaction(&conn, IsolationLevel::RepeatableRead, move |conn| {
+        Box::pin(async move {
+            let recipe = conn
+                .query_opt(queries::RANDOM_RECIPE, &[&user_id])
+                .timed("random_recipe")
+                .await?
+                .ok_or(AppError::NotFound("you don't have any recipes yet"))?;
 
-    let recipes = conn
-        .query(
-            r#"
- SELECT
-	"core_recipe"."id",
-	"core_recipe"."name",
-	"core_recipe"."author",
-	"core_recipe"."source",
-	"core_recipe"."time",
-	"core_recipe"."servings",
-	"core_recipe"."edits",
-	"core_recipe"."modified",
-	"core_team"."id" "team_id",
-	"core_team"."name",
-	"core_myuser"."id" "user_id",
-	"core_recipe"."created",
-	"core_recipe"."archived_at",
-	"core_recipe"."tags"
-FROM
-	"core_recipe"
-	LEFT OUTER JOIN "core_myuser" ON ("core_recipe"."object_id" = "core_myuser"."id"
-		AND("core_recipe"."content_type_id" = 1))
-	LEFT OUTER JOIN "core_team" ON ("core_recipe"."object_id" = "core_team"."id"
-		AND("core_recipe"."content_type_id" = 20))
-WHERE ("core_recipe"."deleted_at" IS NULL
-	AND("core_myuser"."id" = $1
-		OR "core_team"."id" IN(
-			SELECT
-				U0. "team_id" FROM "core_membership" U0
-			WHERE (U0. "user_id" = $1
-				AND U0. "is_active"))))
-order by random() -- hacky solution to get a random recipe to simulate a detail view
-
-limit $2
-;
-        "#,
-            &[&user_id, &limit],
-        )
-        .await
-        .map_err(internal_error)?;
+            Ok(Json(
+                load_recipe(conn, &recipe, user_id, Include::default()).await?,
+            ))
+        })
+    })
+    .await
+}
 
-    let recipe_ids: Vec<i32> = recipes.iter().map(|r| r.get("id")).collect();
+/// List tags
+///
+/// Every tag on the recipes the user can access along with how many recipes
+/// have it, the most used first.
+#[utoipa::path(
+    get,
+    path = "/api/v1/tags",
+    responses(
+        (status = 200, description = "The tags in use", body = [TagCount]),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+    ),
+    security(("session" = []))
+)]
+async fn tags_list(
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> Result<Json<Vec<TagCount>>, AppError> {
+    let user_id = authenticate_primary(&state, &jar).await?;
+    let conn = state.read_pool.get().await?;
 
-    let ingredient_rows = conn
-        .query(
-            r#"
-SELECT
-	"core_ingredient"."id",
-	"core_ingredient"."position",
-	"core_ingredient"."quantity",
-	"core_ingredient"."name",
-	"core_ingredient"."description"
-FROM
-	"core_ingredient"
-WHERE ("core_ingredient"."deleted_at" IS NULL
-	AND "core_ingredient"."recipe_id" = any($1::int[]) )
-ORDER BY
-	"core_ingredient"."position" ASC;
-        "#,
-            &[&recipe_ids],
-        )
-        .await
-        .map_err(internal_error)?;
+    let rows = conn
+        .query(queries::TAG_COUNTS, &[&user_id])
+        .timed("tags")
+        .await?;
 
-    let step_rows = conn
-        .query(
-            r#"
-SELECT
-	"core_step"."id",
-	"core_step"."text",
-	"core_step"."position",
-	"core_step"."recipe_id"
-FROM
-	"core_step"
-WHERE ("core_step"."deleted_at" IS NULL
-	AND "core_step"."recipe_id" = any($1::int[]) )
-ORDER BY
-	"core_step"."position" ASC;
-        "#,
-            &[&recipe_ids],
-        )
-        .await
-        .map_err(internal_error)?;
+    Ok(Json(
+        rows.iter()
+            .map(|row| {
+                Ok(TagCount {
+                    tag: row.column("tag")?,
+                    count: row.column("count")?,
+                })
+            })
+            .collect::<Result<_, AppError>>()?,
+    ))
+}
 
-    let section_rows = conn
-        .query(
-            r#"
-SELECT
-	"core_section"."id",
-	"core_section"."title",
-	"core_section"."position",
-	"core_section"."recipe_id"
-FROM
-	"core_section"
-WHERE ("core_section"."deleted_at" IS NULL
-	AND "core_section"."recipe_id" = any($1::int[]))
-ORDER BY
-	"core_section"."position" ASC;
-"#,
-            &[&recipe_ids],
-        )
-        .await
-        .map_err(internal_error)?;
+#[derive(Deserialize, IntoParams)]
+struct BatchParams {
+    /// Comma separated recipe ids, at most 50
+    ids: String,
+    /// Same as for the detail endpoint
+    include: Option<String>,
+}
 
-    let note_rows = conn
-        .query(
-            r#"
-SELECT
-	"core_note"."id",
-	"core_note"."text",
-	"core_note"."modified",
-	"core_note"."created",
-	"core_note"."recipe_id",
-	"core_note"."last_modified_by_id",
-	"core_myuser"."email",
-	"core_myuser"."name",
-	"core_note"."created_by_id",
-	T4. "email",
-	T4. "name"
-FROM
-	"core_note"
-	LEFT OUTER JOIN "core_myuser" ON ("core_note"."last_modified_by_id" = "core_myuser"."id")
-INNER JOIN "core_myuser" T4 ON ("core_note"."created_by_id" = T4. "id")
-WHERE ("core_note"."deleted_at" IS NULL
-	AND "core_note"."recipe_id" = any($1::int[]))
-ORDER BY
-	"core_note"."created" DESC;
-
-        "#,
-            &[&recipe_ids],
-        )
-        .await
-        .map_err(internal_error)?;
+impl BatchParams {
+    const MAX_IDS: usize = 50;
+}
 
-    let reaction_rows = conn
-        .query(
-            r#"
-SELECT
-	"core_reaction"."id",
-	"core_reaction"."created",
-	"core_reaction"."modified",
-	"core_reaction"."emoji",
-	"core_reaction"."created_by_id",
-	"core_reaction"."note_id"
-FROM
-	"core_reaction"
-	INNER JOIN "core_note" ON ("core_reaction"."note_id" = "core_note"."id")
-WHERE
-	"core_note"."recipe_id" = any($1::int[])
-ORDER BY
-	"core_reaction"."created" DESC;
-        "#,
-            &[&recipe_ids],
-        )
+/// The ids in the order given without duplicates, or a 422 if there are none,
+/// too many or they aren't numbers.
+fn parse_ids(ids: &str) -> Result<Vec<i32>, AppError> {
+    let ids = ids
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse()
+                .map_err(|_| AppError::Unprocessable(format!("ids: invalid id {}", id)))
+        })
+        .collect::<Result<_, _>>()?;
+    dedupe_ids(ids)
+}
+
+/// `ids` in the order given without duplicates, or a 422 if there are none or
+/// too many.
+fn dedupe_ids(ids: Vec<i32>) -> Result<Vec<i32>, AppError> {
+    let mut deduped: Vec<i32> = vec![];
+    for id in ids {
+        if !deduped.contains(&id) {
+            deduped.push(id);
+        }
+    }
+    if deduped.is_empty() {
+        return Err(AppError::Unprocessable("ids: can't be empty".into()));
+    }
+    if deduped.len() > BatchParams::MAX_IDS {
+        return Err(AppError::Unprocessable(format!(
+            "ids: at most {} at a time",
+            BatchParams::MAX_IDS
+        )));
+    }
+    Ok(deduped)
+}
+
+/// Get several recipes
+///
+/// The full bundles for each of `ids`, in the same order. Ids that don't exist
+/// or the user can't access are left out rather than failing the batch.
+#[utoipa::path(
+    get,
+    path = "/api/v1/recipes/batch",
+    params(BatchParams),
+    responses(
+        (status = 200, description = "The recipes", body = [Recipe]),
+        (status = 422, description = "No ids, more than 50, an invalid id, or an unknown `include`"),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+    ),
+    security(("session" = []))
+)]
+async fn recipes_batch(
+    State(state): State<AppState>,
+    Query(params): Query<BatchParams>,
+    jar: CookieJar,
+) -> Result<Json<Vec<Recipe>>, AppError> {
+    let recipe_ids = parse_ids(&params.ids)?;
+    let include = match params.include {
+        Some(include) => Include::parse(&include)?,
+        None => Include::default(),
+    };
+    load_batch(&state, &jar, recipe_ids, include)
         .await
-        .map_err(internal_error)?;
+        .map(Json)
+}
 
-    let timeline_rows = conn
-        .query(
-            r#"
-SELECT
-	"timeline_event"."id",
-	"timeline_event"."action",
-	"timeline_event"."created",
-	"timeline_event"."created_by_id",
-	"core_myuser"."email",
-	"timeline_event"."recipe_id"
-FROM
-	"timeline_event"
-	LEFT OUTER JOIN "core_myuser" ON ("timeline_event"."created_by_id" = "core_myuser"."id")
-WHERE ("timeline_event"."deleted_at" IS NULL
-	AND "timeline_event"."recipe_id" = any($1::int[]))
-ORDER BY
-	"timeline_event"."created" DESC;
-
-        "#,
-            &[&recipe_ids],
-        )
+#[derive(Deserialize, ToSchema)]
+struct BatchRequest {
+    /// At most 50
+    ids: Vec<i32>,
+}
+
+/// Get several recipes by POST
+///
+/// Same as `GET /api/v1/recipes/batch` with the ids in the body, for id lists
+/// that would make for an unwieldy URL. It only reads, so it doesn't need an
+/// `X-CSRF-Token`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/recipes/batch",
+    params(DetailParams),
+    request_body = BatchRequest,
+    responses(
+        (status = 200, description = "The recipes", body = [Recipe]),
+        (status = 422, description = "No ids, more than 50, an unknown `include` or `timeline`, or a body that doesn't deserialize"),
+        (status = 415, description = "The body isn't JSON"),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+    ),
+    security(("session" = []))
+)]
+async fn recipes_batch_post(
+    State(state): State<AppState>,
+    Query(params): Query<DetailParams>,
+    jar: CookieJar,
+    Json(payload): Json<BatchRequest>,
+) -> Result<Json<Vec<Recipe>>, AppError> {
+    let recipe_ids = dedupe_ids(payload.ids)?;
+    let include = params.include()?;
+    load_batch(&state, &jar, recipe_ids, include)
         .await
-        .map_err(internal_error)?;
-
-    let mut ingredients = vec![];
-    for i in ingredient_rows {
-        ingredients.push(IngredientLike::Ingredient(Ingredient {
-            id: i.get("id"),
-            position: i.get("position"),
-            quantity: i.get("quantity"),
-            name: i.get("name"),
-            description: i.get("description"),
-        }))
-    }
-    for sec in section_rows {
-        ingredients.push(IngredientLike::Section(Section {
-            id: sec.get("id"),
-            title: sec.get("title"),
-            position: sec.get("position"),
-        }))
-    }
-
-    let steps = step_rows
-        .into_iter()
-        .map(|s| Step {
-            id: s.get("id"),
-            position: s.get("position"),
-            text: s.get("text"),
-        })
-        .collect();
+        .map(Json)
+}
 
-    let mut reactions: HashMap<i32, Vec<Reaction>> = HashMap::new();
-    for r in reaction_rows {
-        reactions
-            .entry(r.get("note_id"))
-            .or_insert_with(|| vec![])
-            .push(Reaction {
-                id: r.get("id"),
-                emoji: r.get("emoji"),
-                created_by_id: r.get("created_by_id"),
+/// The full bundles for the accessible recipes out of `recipe_ids`, in the
+/// same order.
+async fn load_batch(
+    state: &AppState,
+    jar: &CookieJar,
+    recipe_ids: Vec<i32>,
+    include: Include,
+) -> Result<Vec<Recipe>, AppError> {
+    let user_id = authenticate_primary(state, jar).await?;
+
+    let conn = state.read_pool.get().await?;
+    in_transaction(&conn, IsolationLevel::RepeatableRead, move |conn| {
+        Box::pin(async move {
+            let mut rows = recipe_rows_by_id(conn, user_id, &recipe_ids).await?;
+            rows.sort_by_key(|row| {
+                // a missing id fails in `load_recipes` below
+                let id: Option<i32> = row.column("id").ok();
+                recipe_ids
+                    .iter()
+                    .position(|requested| Some(*requested) == id)
             });
+            load_recipes(conn, &rows, user_id, include).await
+        })
+    })
+    .await
+}
+
+#[derive(Deserialize, IntoParams)]
+struct DetailParams {
+    /// Comma separated subset of `ingredients`, `steps`, `timeline`, `notes`
+    /// and `reactions` to return, everything by default
+    include: Option<String>,
+    /// `notes` or `events` to only get those in the timeline, `all` by
+    /// default
+    timeline: Option<String>,
+}
+
+impl DetailParams {
+    /// `include` narrowed down by `timeline`.
+    fn include(&self) -> Result<Include, AppError> {
+        let mut include = match self.include.as_deref() {
+            Some(include) => Include::parse(include)?,
+            None => Include::default(),
+        };
+        match self.timeline.as_deref() {
+            None | Some("all") => {}
+            Some("notes") => include.timeline = false,
+            Some("events") => include.notes = false,
+            Some(other) => {
+                return Err(AppError::Unprocessable(format!(
+                    "timeline: unknown filter {}",
+                    other
+                )))
+            }
+        }
+        Ok(include)
     }
+}
 
-    let mut timeline: Vec<TimelineLike> = vec![];
-    for t in timeline_rows {
-        timeline.push(TimelineLike::TimelineEvent(TimelineEvent {
-            id: t.get("id"),
-            action: t.get("action"),
-            created_at: t.get("created"),
-            created_by_id: t.get("created_by_id"),
-            created_by_name: t.get("email"),
-        }))
-    }
-    for n in note_rows {
-        timeline.push(TimelineLike::Note(Note {
-            id: n.get("id"),
-            text: n.get("text"),
-            email: n.get("email"),
-            name: n.get("name"),
-            modified_at: n.get("modified"),
-            created_at: n.get("created"),
-            reactions: reactions.entry(n.get("id")).or_default().clone(),
-        }))
-    }
-
-    let recipe = &recipes[0];
-    return Ok(Json(Recipe {
-        id: recipe.get("id"),
-        name: recipe.get("name"),
-        author: recipe.get("author"),
-        source: recipe.get("source"),
-        time: recipe.get("time"),
-        servings: recipe.get("servings"),
-        tags: recipe.get("tags"),
-        archived_at: recipe.get("archived_at"),
-        created_at: recipe.get("created"),
-        ingredients,
-        steps,
-        timeline,
-    }));
-}
-
-/// response.
-fn internal_error<E>(err: E) -> (StatusCode, String)
-where
-    E: std::error::Error,
-{
-    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+/// The parts of the recipe bundle to load.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Include {
+    /// Sections too, they're interleaved with the ingredients
+    ingredients: bool,
+    steps: bool,
+    /// Timeline events only, notes are separate even though they're returned
+    /// in the timeline
+    timeline: bool,
+    notes: bool,
+    /// Only has an effect along with `notes`
+    reactions: bool,
+}
+
+impl Default for Include {
+    fn default() -> Self {
+        Include {
+            ingredients: true,
+            steps: true,
+            timeline: true,
+            notes: true,
+            reactions: true,
+        }
+    }
+}
+
+impl Include {
+    /// What the list loads without an `include`, a page of recipes with all
+    /// their notes and reactions adds up
+    const LIST: Include = Include {
+        ingredients: true,
+        steps: true,
+        timeline: false,
+        notes: false,
+        reactions: false,
+    };
+
+    fn parse(include: &str) -> Result<Self, AppError> {
+        let mut parsed = Include {
+            ingredients: false,
+            steps: false,
+            timeline: false,
+            notes: false,
+            reactions: false,
+        };
+        for part in include.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match part {
+                "ingredients" => parsed.ingredients = true,
+                "steps" => parsed.steps = true,
+                "timeline" => parsed.timeline = true,
+                "notes" => parsed.notes = true,
+                "reactions" => parsed.reactions = true,
+                _ => {
+                    return Err(AppError::Unprocessable(format!(
+                        "include: unknown include {}",
+                        part
+                    )))
+                }
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// Get a recipe by id
+#[utoipa::path(
+    get,
+    path = "/api/v1/recipes/{id}",
+    params(("id" = i32, Path, description = "Recipe id"), DetailParams),
+    responses(
+        (status = 200, description = "The recipe", body = Recipe,
+            headers(
+                ("etag" = String, description = "Weak ETag of the recipe bundle"),
+                ("last-modified" = String, description = "When the recipe or its ingredients, sections, steps, notes, reactions or timeline last changed"),
+            )),
+        (status = 304, description = "Recipe hasn't changed since the `If-None-Match` ETag, or `If-Modified-Since` without an `If-None-Match`"),
+        (status = 422, description = "Unknown section in `include` or filter in `timeline`"),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 404, description = "Recipe doesn't exist or the user can't access it"),
+    ),
+    security(("session" = []))
+)]
+async fn recipe_detail(
+    State(state): State<AppState>,
+    Path(recipe_id): Path<i32>,
+    Query(params): Query<DetailParams>,
+    headers: HeaderMap,
+    jar: CookieJar,
+) -> Result<Response, AppError> {
+    Span::current().record("recipe_id", recipe_id);
+    let include = params.include()?;
+    let user_id = authenticate_primary(&state, &jar).await?;
+
+    // same snapshot for the ETag and the bundle so the ETag always describes
+    // what we send
+    let conn = state.read_pool.get().await?;
+    in_transaction(&conn, IsolationLevel::RepeatableRead, move |conn| {
+        Box::pin(async move {
+            // doubles as the access check, which has to run on every request since
+            // the user could have lost access since their bundle was cached
+            let RecipeVersion {
+                etag,
+                last_modified,
+            } = recipe_version(conn, user_id, recipe_id, include)
+                .await?
+                .ok_or(AppError::NotFound("recipe not found"))?;
+            let validators = [
+                (header::ETAG, etag.clone()),
+                (header::LAST_MODIFIED, http_date(last_modified)),
+            ];
+
+            // If-Modified-Since is only a fallback for clients without an ETag,
+            // and a malformed date is ignored
+            let not_modified = match headers.get(header::IF_NONE_MATCH) {
+                Some(if_none_match) => if_none_match
+                    .to_str()
+                    .is_ok_and(|tags| etag_matches(tags, &etag)),
+                None => headers
+                    .get(header::IF_MODIFIED_SINCE)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|date| chrono::DateTime::parse_from_rfc2822(date).ok())
+                    // the header only has second precision
+                    .is_some_and(|since| last_modified.timestamp() <= since.timestamp()),
+            };
+            if not_modified {
+                return Ok((StatusCode::NOT_MODIFIED, validators).into_response());
+            }
+
+            // only full bundles are cached, and an entry from before the latest
+            // change is as good as a miss
+            let cache = state.recipe_cache.filter(|_| include == Include::default());
+            if let Some(recipe) = cache
+                .as_ref()
+                .and_then(|cache| cache.get(&(user_id, recipe_id)))
+                .filter(|recipe| recipe.etag.as_ref() == Some(&etag))
+            {
+                tracing::debug!("recipe cache hit");
+                return Ok((validators, Json(recipe)).into_response());
+            }
+
+            let recipe = recipe_row(conn, user_id, recipe_id)
+                .await?
+                .ok_or(AppError::NotFound("recipe not found"))?;
+
+            let mut recipe = load_recipe(conn, &recipe, user_id, include).await?;
+            recipe.etag = Some(etag);
+            let recipe = Arc::new(recipe);
+            if let Some(cache) = &cache {
+                cache.insert((user_id, recipe_id), recipe.clone()).await;
+            }
+            Ok((validators, Json(recipe)).into_response())
+        })
+    })
+    .await
+}
+
+/// Delete a recipe
+///
+/// This is a soft delete, only `core_recipe.deleted_at` is set. The
+/// ingredients, steps, notes, etc. are left untouched, they're unreachable
+/// since every read goes through the recipe, and they come back as they were
+/// if the recipe is restored.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/recipes/{id}",
+    params(("id" = i32, Path, description = "Recipe id")),
+    responses(
+        (status = 204, description = "The recipe was deleted"),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 403, description = "Missing or incorrect `X-CSRF-Token`"),
+        (status = 404, description = "Recipe doesn't exist, is already deleted, or the user can't access it"),
+    ),
+    security(("session" = []))
+)]
+async fn delete_recipe(
+    State(state): State<AppState>,
+    Path(recipe_id): Path<i32>,
+    jar: CookieJar,
+) -> Result<StatusCode, AppError> {
+    Span::current().record("recipe_id", recipe_id);
+    let conn = get_conn(&state.pool).await?;
+    let user_id = authenticate(&conn, &state, &jar).await?;
+
+    let now_utc = Utc::now();
+    let deleted = conn
+        .execute(queries::DELETE_RECIPE, &[&user_id, &recipe_id, &now_utc])
+        .timed("delete_recipe")
+        .await?;
+
+    if deleted == 0 {
+        return Err(AppError::NotFound("recipe not found"));
+    }
+    invalidate_recipe(state.recipe_cache.as_ref(), recipe_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Fields left out are unchanged. `author` and `source` are nullable, so
+/// there's a difference between leaving them out and setting them to `null`.
+#[derive(Deserialize, ToSchema)]
+struct UpdateRecipe {
+    name: Option<String>,
+    #[serde(default, deserialize_with = "double_option")]
+    #[schema(nullable)]
+    author: Option<Option<String>>,
+    #[serde(default, deserialize_with = "double_option")]
+    #[schema(nullable)]
+    source: Option<Option<String>>,
+    time: Option<String>,
+    servings: Option<String>,
+    tags: Option<Vec<String>>,
+}
+
+/// Distinguish a field that's present but `null` (`Some(None)`) from a
+/// missing field (`None`, via `#[serde(default)]`).
+fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Update a recipe
+#[utoipa::path(
+    patch,
+    path = "/api/v1/recipes/{id}",
+    params(("id" = i32, Path, description = "Recipe id")),
+    request_body = UpdateRecipe,
+    responses(
+        (status = 200, description = "The updated recipe", body = Recipe),
+        (status = 400, description = "The name is empty, or there's no body"),
+        (status = 422, description = "The body doesn't deserialize, the message names the field"),
+        (status = 415, description = "The body isn't JSON"),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 403, description = "Missing or incorrect `X-CSRF-Token`"),
+        (status = 404, description = "Recipe doesn't exist or the user can't access it"),
+    ),
+    security(("session" = []))
+)]
+async fn update_recipe(
+    State(state): State<AppState>,
+    Path(recipe_id): Path<i32>,
+    jar: CookieJar,
+    Json(payload): Json<UpdateRecipe>,
+) -> Result<Json<Recipe>, AppError> {
+    Span::current().record("recipe_id", recipe_id);
+    if payload
+        .name
+        .as_ref()
+        .is_some_and(|name| name.trim().is_empty())
+    {
+        return Err(AppError::BadRequest("name can't be empty".into()));
+    }
+
+    let conn = get_conn(&state.pool).await?;
+    let user_id = authenticate(&conn, &state, &jar).await?;
+
+    let recipe = in_transaction(&conn, IsolationLevel::ReadCommitted, move |conn| {
+        Box::pin(async move {
+            let now_utc = Utc::now();
+            let recipe = conn
+                .query_opt(
+                    queries::UPDATE_RECIPE,
+                    &[
+                        &user_id,
+                        &recipe_id,
+                        &now_utc,
+                        &payload.name,
+                        &payload.author.is_some(),
+                        &payload.author.flatten(),
+                        &payload.source.is_some(),
+                        &payload.source.flatten(),
+                        &payload.time,
+                        &payload.servings,
+                        &payload.tags,
+                    ],
+                )
+                .timed("update_recipe")
+                .await?
+                .ok_or(AppError::NotFound("recipe not found"))?;
+            record_timeline_event(conn, recipe_id, user_id, TimelineAction::Edited).await?;
+            Ok(recipe)
+        })
+    })
+    .await?;
+    invalidate_recipe(state.recipe_cache.as_ref(), recipe_id);
+
+    Ok(Json(
+        load_recipe(&conn, &recipe, user_id, Include::default()).await?,
+    ))
+}
+
+/// Archive a recipe
+///
+/// Archiving an archived recipe is a no-op and keeps the original `archived_at`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/recipes/{id}/archive",
+    params(("id" = i32, Path, description = "Recipe id")),
+    responses(
+        (status = 200, description = "The archived recipe", body = RecipeSummary),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 403, description = "Missing or incorrect `X-CSRF-Token`"),
+        (status = 404, description = "Recipe doesn't exist or the user can't access it"),
+    ),
+    security(("session" = []))
+)]
+async fn archive_recipe(
+    State(state): State<AppState>,
+    Path(recipe_id): Path<i32>,
+    jar: CookieJar,
+) -> Result<Json<RecipeSummary>, AppError> {
+    Span::current().record("recipe_id", recipe_id);
+    let conn = get_conn(&state.pool).await?;
+    let user_id = authenticate(&conn, &state, &jar).await?;
+    let recipe = set_archived(&conn, user_id, recipe_id, true)
+        .await?
+        .ok_or(AppError::NotFound("recipe not found"))?;
+    invalidate_recipe(state.recipe_cache.as_ref(), recipe_id);
+    Ok(Json(recipe))
+}
+
+/// Unarchive a recipe
+///
+/// Unarchiving a recipe that isn't archived is a no-op.
+#[utoipa::path(
+    post,
+    path = "/api/v1/recipes/{id}/unarchive",
+    params(("id" = i32, Path, description = "Recipe id")),
+    responses(
+        (status = 200, description = "The unarchived recipe", body = RecipeSummary),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 403, description = "Missing or incorrect `X-CSRF-Token`"),
+        (status = 404, description = "Recipe doesn't exist or the user can't access it"),
+    ),
+    security(("session" = []))
+)]
+async fn unarchive_recipe(
+    State(state): State<AppState>,
+    Path(recipe_id): Path<i32>,
+    jar: CookieJar,
+) -> Result<Json<RecipeSummary>, AppError> {
+    Span::current().record("recipe_id", recipe_id);
+    let conn = get_conn(&state.pool).await?;
+    let user_id = authenticate(&conn, &state, &jar).await?;
+    let recipe = set_archived(&conn, user_id, recipe_id, false)
+        .await?
+        .ok_or(AppError::NotFound("recipe not found"))?;
+    invalidate_recipe(state.recipe_cache.as_ref(), recipe_id);
+    Ok(Json(recipe))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ArchiveRecipe {
+    archived: bool,
+}
+
+/// Archive or unarchive a recipe
+///
+/// Same as the `archive` and `unarchive` endpoints, except that a recipe the
+/// user can't access is a 403 rather than a 404.
+#[utoipa::path(
+    patch,
+    path = "/api/v1/recipes/{id}/archive",
+    params(("id" = i32, Path, description = "Recipe id")),
+    request_body = ArchiveRecipe,
+    responses(
+        (status = 200, description = "The updated recipe", body = RecipeSummary),
+        (status = 400, description = "There's no body"),
+        (status = 422, description = "The body doesn't deserialize, the message names the field"),
+        (status = 415, description = "The body isn't JSON"),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 403, description = "The user can't access the recipe, or the `X-CSRF-Token` is missing or incorrect"),
+        (status = 404, description = "Recipe doesn't exist"),
+    ),
+    security(("session" = []))
+)]
+async fn update_archived(
+    State(state): State<AppState>,
+    Path(recipe_id): Path<i32>,
+    jar: CookieJar,
+    Json(payload): Json<ArchiveRecipe>,
+) -> Result<Json<RecipeSummary>, AppError> {
+    Span::current().record("recipe_id", recipe_id);
+    let conn = get_conn(&state.pool).await?;
+    let user_id = authenticate(&conn, &state, &jar).await?;
+    match set_archived(&conn, user_id, recipe_id, payload.archived).await? {
+        Some(recipe) => {
+            invalidate_recipe(state.recipe_cache.as_ref(), recipe_id);
+            Ok(Json(recipe))
+        }
+        None if recipe_exists(&conn, recipe_id).await? => {
+            Err(AppError::Forbidden("you can't edit this recipe"))
+        }
+        None => Err(AppError::NotFound("recipe not found")),
+    }
+}
+
+async fn set_archived(
+    conn: &PgConnection,
+    user_id: i32,
+    recipe_id: i32,
+    archived: bool,
+) -> Result<Option<RecipeSummary>, AppError> {
+    in_transaction(conn, IsolationLevel::ReadCommitted, move |conn| {
+        Box::pin(async move {
+            let now_utc = Utc::now();
+            // only bump `modified` when the state actually changes so a no-op
+            // doesn't invalidate the ETag, or add to the timeline
+            let Some(recipe) = conn
+                .query_opt(
+                    queries::SET_ARCHIVED,
+                    &[&user_id, &recipe_id, &archived, &now_utc],
+                )
+                .timed("archive_recipe")
+                .await?
+            else {
+                return Ok(None);
+            };
+            if recipe.column("changed")? {
+                let action = if archived {
+                    TimelineAction::Archived
+                } else {
+                    TimelineAction::Unarchived
+                };
+                record_timeline_event(conn, recipe_id, user_id, action).await?;
+            }
+            RecipeSummary::from_row(&recipe).map(Some)
+        })
+    })
+    .await
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CreateNote {
+    text: String,
+}
+
+/// Add a note to a recipe
+#[utoipa::path(
+    post,
+    path = "/api/v1/recipes/{id}/notes",
+    params(("id" = i32, Path, description = "Recipe id")),
+    request_body = CreateNote,
+    responses(
+        (status = 201, description = "The created note", body = Note),
+        (status = 400, description = "The note text is empty, or there's no body"),
+        (status = 422, description = "The body doesn't deserialize, the message names the field"),
+        (status = 415, description = "The body isn't JSON"),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 403, description = "Missing or incorrect `X-CSRF-Token`"),
+        (status = 404, description = "Recipe doesn't exist or the user can't access it"),
+    ),
+    security(("session" = []))
+)]
+async fn create_note(
+    State(state): State<AppState>,
+    Path(recipe_id): Path<i32>,
+    jar: CookieJar,
+    Json(payload): Json<CreateNote>,
+) -> Result<(StatusCode, Json<Note>), AppError> {
+    Span::current().record("recipe_id", recipe_id);
+    if payload.text.trim().is_empty() {
+        return Err(AppError::BadRequest("note text can't be empty".into()));
+    }
+
+    let conn = get_conn(&state.pool).await?;
+    let user_id = authenticate(&conn, &state, &jar).await?;
+
+    let n = in_transaction(&conn, IsolationLevel::ReadCommitted, move |conn| {
+        Box::pin(async move {
+            if !can_access_recipe(conn, user_id, recipe_id).await? {
+                return Err(AppError::NotFound("recipe not found"));
+            }
+
+            let now_utc = Utc::now();
+            let n = conn
+                .query_one(
+                    queries::CREATE_NOTE,
+                    &[&payload.text, &now_utc, &recipe_id, &user_id],
+                )
+                .timed("create_note")
+                .await?;
+            record_timeline_event(conn, recipe_id, user_id, TimelineAction::NoteCreated).await?;
+            // Postgres holds the notification until the commit
+            notify::publish(conn, recipe_id, ChangeKind::NoteCreated).await?;
+            Ok(n)
+        })
+    })
+    .await?;
+    invalidate_recipe(state.recipe_cache.as_ref(), recipe_id);
+
+    Ok((
+        StatusCode::CREATED,
+        Json(Note {
+            reactions: Some(vec![]),
+            reaction_summary: Some(vec![]),
+            ..Note::from_row(&n)?
+        }),
+    ))
+}
+
+/// Get a recipe's ingredients
+///
+/// The ingredients and section headers in order, the same as the recipe's
+/// `ingredients`, for when that's all that's needed, e.g. a shopping list.
+#[utoipa::path(
+    get,
+    path = "/api/v1/recipes/{id}/ingredients",
+    params(("id" = i32, Path, description = "Recipe id")),
+    responses(
+        (status = 200, description = "The ingredients and sections", body = [IngredientLike]),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 404, description = "Recipe doesn't exist or the user can't access it"),
+    ),
+    security(("session" = []))
+)]
+async fn recipe_ingredients_list(
+    State(state): State<AppState>,
+    Path(recipe_id): Path<i32>,
+    jar: CookieJar,
+) -> Result<Json<Vec<IngredientLike>>, AppError> {
+    Span::current().record("recipe_id", recipe_id);
+    let user_id = authenticate_primary(&state, &jar).await?;
+
+    let conn = state.read_pool.get().await?;
+    in_transaction(&conn, IsolationLevel::RepeatableRead, move |conn| {
+        Box::pin(async move {
+            if !can_access_recipe(conn, user_id, recipe_id).await? {
+                return Err(AppError::NotFound("recipe not found"));
+            }
+            let ingredients = recipe_ingredient_likes(conn, &[recipe_id])
+                .await?
+                .remove(&recipe_id)
+                .unwrap_or_default();
+            Ok(Json(ingredients))
+        })
+    })
+    .await
+}
+
+#[derive(Deserialize, IntoParams)]
+struct NotesParams {
+    /// Page size, clamped to between 1 and 100, 20 by default
+    limit: Option<i64>,
+    /// Number of notes to skip, 0 by default
+    offset: Option<i64>,
+}
+
+/// Get a recipe's notes
+///
+/// Newest first with their reactions, a page at a time so a long history can
+/// be loaded as it's scrolled to. The `Link` header has the next page,
+/// there's none once a page comes back short.
+#[utoipa::path(
+    get,
+    path = "/api/v1/recipes/{id}/notes",
+    params(("id" = i32, Path, description = "Recipe id"), NotesParams),
+    responses(
+        (status = 200, description = "A page of notes", body = [Note]),
+        (status = 422, description = "`limit` or `offset` doesn't parse, or a negative `offset`"),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 404, description = "Recipe doesn't exist or the user can't access it"),
+    ),
+    security(("session" = []))
+)]
+async fn recipe_notes_list(
+    State(state): State<AppState>,
+    Path(recipe_id): Path<i32>,
+    Query(params): Query<NotesParams>,
+    jar: CookieJar,
+) -> Result<Response, AppError> {
+    Span::current().record("recipe_id", recipe_id);
+    if params.offset.is_some_and(|offset| offset < 0) {
+        return Err(AppError::Unprocessable("offset: can't be negative".into()));
+    }
+    let page = TimelinePage {
+        after: None,
+        limit: params.limit.unwrap_or(20).clamp(1, RecipeQuery::MAX_LIMIT),
+        offset: params.offset.unwrap_or(0),
+    };
+    let user_id = authenticate_primary(&state, &jar).await?;
+
+    let conn = state.read_pool.get().await?;
+    in_transaction(&conn, IsolationLevel::RepeatableRead, move |conn| {
+        Box::pin(async move {
+            if !can_access_recipe(conn, user_id, recipe_id).await? {
+                return Err(AppError::NotFound("recipe not found"));
+            }
+            let notes = recipe_notes(conn, &[recipe_id], user_id, true, Some(page))
+                .await?
+                .remove(&recipe_id)
+                .unwrap_or_default();
+
+            let full = notes.len() as i64 == page.limit;
+            let mut response = Json(notes).into_response();
+            if full {
+                let next = format!(
+                    r#"</api/v1/recipes/{}/notes?limit={}&offset={}>; rel="next""#,
+                    recipe_id,
+                    page.limit,
+                    page.offset + page.limit
+                );
+                response.headers_mut().insert(
+                    header::LINK,
+                    HeaderValue::from_str(&next).expect("numbers are url safe"),
+                );
+            }
+            Ok(response)
+        })
+    })
+    .await
+}
+
+#[derive(Deserialize, IntoParams)]
+struct TimelineParams {
+    /// Page size, clamped to between 1 and 100, 20 by default
+    limit: Option<i64>,
+    /// The cursor from the previous page's `Link`, can't be combined with
+    /// `before`
+    after: Option<String>,
+    /// Only entries created before this
+    before: Option<chrono::DateTime<Utc>>,
+}
+
+impl TimelineParams {
+    /// `after`, or `before` as a cursor that everything created at that time
+    /// sorts after.
+    fn cursor(&self) -> Result<Option<TimelineCursor>, AppError> {
+        match (&self.after, self.before) {
+            (Some(_), Some(_)) => Err(AppError::Unprocessable(
+                "after: can't be combined with before".into(),
+            )),
+            (Some(after), None) => TimelineCursor::decode(after)
+                .map(Some)
+                .ok_or(AppError::Unprocessable("after: invalid cursor".into())),
+            (None, before) => Ok(before.map(|created| TimelineCursor {
+                created,
+                kind: i32::MIN,
+                id: i32::MIN,
+            })),
+        }
+    }
+}
+
+/// Where a page of the timeline ended, the [`TimelineLike::key`] of its last
+/// entry. Notes and events created at the same time are told apart by the
+/// kind and id, so like a [`Cursor`] none are skipped or repeated.
+#[derive(Clone, Copy)]
+struct TimelineCursor {
+    created: chrono::DateTime<Utc>,
+    /// [`Self::EVENT`] or [`Self::NOTE`]
+    kind: i32,
+    id: i32,
+}
+
+impl TimelineCursor {
+    /// The kinds' places in the order, the queries have them hardcoded
+    const EVENT: i32 = 0;
+    const NOTE: i32 = 1;
+
+    fn encode(&self) -> String {
+        let cursor = format!(
+            "{}:{}:{}",
+            self.created.timestamp_micros(),
+            self.kind,
+            self.id
+        );
+        base64::encode_config(cursor, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let cursor = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?;
+        let mut parts = std::str::from_utf8(&cursor).ok()?.splitn(3, ':');
+        let created = from_micros(parts.next()?.parse().ok()?)?;
+        let kind = parts.next()?.parse().ok()?;
+        let id = parts.next()?.parse().ok()?;
+        Some(TimelineCursor { created, kind, id })
+    }
+}
+
+/// A page of a recipe's timeline, the newest entries after `after`.
+#[derive(Clone, Copy)]
+struct TimelinePage {
+    after: Option<TimelineCursor>,
+    limit: i64,
+    /// Only the notes endpoint pages by offset, the timeline merges two kinds
+    /// of entry so it goes by `after`
+    offset: i64,
+}
+
+/// Get a recipe's timeline
+///
+/// The notes and timeline events merged, newest first, a page at a time so
+/// the recipe itself can be loaded without them. The `Link` header has the
+/// next page, there's none once a page comes back short. Entries created at
+/// the same time go notes first, then the newest id, so paging never skips
+/// one.
+#[utoipa::path(
+    get,
+    path = "/api/v1/recipes/{id}/timeline",
+    params(("id" = i32, Path, description = "Recipe id"), TimelineParams),
+    responses(
+        (status = 200, description = "A page of the timeline", body = [TimelineLike]),
+        (status = 422, description = "`limit` or `before` doesn't parse, an invalid `after` cursor, or both of them"),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 404, description = "Recipe doesn't exist or the user can't access it"),
+    ),
+    security(("session" = []))
+)]
+async fn recipe_timeline(
+    State(state): State<AppState>,
+    Path(recipe_id): Path<i32>,
+    Query(params): Query<TimelineParams>,
+    jar: CookieJar,
+) -> Result<Response, AppError> {
+    Span::current().record("recipe_id", recipe_id);
+    let page = TimelinePage {
+        after: params.cursor()?,
+        limit: params.limit.unwrap_or(20).clamp(1, RecipeQuery::MAX_LIMIT),
+        offset: 0,
+    };
+    let user_id = authenticate_primary(&state, &jar).await?;
+
+    let conn = state.read_pool.get().await?;
+    in_transaction(&conn, IsolationLevel::RepeatableRead, move |conn| {
+        Box::pin(async move {
+            if !can_access_recipe(conn, user_id, recipe_id).await? {
+                return Err(AppError::NotFound("recipe not found"));
+            }
+
+            // a page of each, then the newest of both
+            let events = recipe_timeline_events(conn, &[recipe_id], Some(page))
+                .await?
+                .remove(&recipe_id)
+                .unwrap_or_default();
+            let notes = recipe_notes(conn, &[recipe_id], user_id, true, Some(page))
+                .await?
+                .remove(&recipe_id)
+                .unwrap_or_default();
+            let mut timeline: Vec<TimelineLike> = events
+                .into_iter()
+                .map(TimelineLike::TimelineEvent)
+                .chain(notes.into_iter().map(TimelineLike::Note))
+                .collect();
+            timeline.sort_by_key(|entry| std::cmp::Reverse(entry.key()));
+            timeline.truncate(page.limit as usize);
+
+            let next = timeline
+                .last()
+                .filter(|_| timeline.len() as i64 == page.limit)
+                .map(|last| {
+                    let (created, kind, id) = last.key();
+                    format!(
+                        r#"</api/v1/recipes/{}/timeline?limit={}&after={}>; rel="next""#,
+                        recipe_id,
+                        page.limit,
+                        TimelineCursor { created, kind, id }.encode()
+                    )
+                });
+            let mut response = Json(timeline).into_response();
+            if let Some(next) = next {
+                response.headers_mut().insert(
+                    header::LINK,
+                    HeaderValue::from_str(&next).expect("cursor is url safe"),
+                );
+            }
+            Ok(response)
+        })
+    })
+    .await
+}
+
+/// Stream a recipe's timeline changes
+///
+/// A `text/event-stream` with an event whenever a note is added to the recipe
+/// or a reaction is added or removed, named after the `kind` of change. The events don't include the
+/// change itself, refetch the recipe to get it. A `resync` event means some
+/// changes were dropped because the client fell behind.
+#[utoipa::path(
+    get,
+    path = "/api/v1/recipes/{id}/timeline/stream",
+    params(("id" = i32, Path, description = "Recipe id")),
+    responses(
+        (status = 200, description = "Stream of timeline changes", body = String,
+            content_type = "text/event-stream"),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 404, description = "Recipe doesn't exist or the user can't access it"),
+    ),
+    security(("session" = []))
+)]
+async fn timeline_stream(
+    State(state): State<AppState>,
+    Path(recipe_id): Path<i32>,
+    jar: CookieJar,
+) -> Result<Sse<impl Stream<Item = Result<Event, serde_json::Error>>>, AppError> {
+    Span::current().record("recipe_id", recipe_id);
+    // subscribe first so nothing is missed between the access check and the
+    // stream starting. The receiver is dropped along with the stream when the
+    // client goes away.
+    let changes = state.timeline_changes.subscribe();
+
+    let conn = get_conn(&state.pool).await?;
+    let user_id = authenticate(&conn, &state, &jar).await?;
+    if !can_access_recipe(&conn, user_id, recipe_id).await? {
+        return Err(AppError::NotFound("recipe not found"));
+    }
+    let stream = stream::unfold(changes, move |mut changes| async move {
+        loop {
+            match changes.recv().await {
+                Ok(change) if change.recipe_id == recipe_id => {
+                    let event = Event::default()
+                        .event(change.kind.as_str())
+                        .json_data(&change);
+                    return Some((event, changes));
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(_)) => {
+                    return Some((Ok(Event::default().event("resync").data("")), changes))
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ReactionPayload {
+    emoji: String,
+}
+
+impl ReactionPayload {
+    fn validate(&self) -> Result<(), AppError> {
+        // a single emoji can be several code points (skin tones, ZWJ
+        // sequences) but it's always one grapheme
+        if self.emoji.graphemes(true).count() != 1 || self.emoji.len() > 32 {
+            return Err(AppError::BadRequest(
+                "emoji should be a single character".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// React to a note
+///
+/// Adding a reaction the user already has is a no-op.
+#[utoipa::path(
+    post,
+    path = "/api/v1/notes/{id}/reactions",
+    params(("id" = i32, Path, description = "Note id")),
+    request_body = ReactionPayload,
+    responses(
+        (status = 200, description = "All the reactions on the note", body = [Reaction]),
+        (status = 400, description = "The emoji isn't a single character, or there's no body"),
+        (status = 422, description = "The body doesn't deserialize, the message names the field"),
+        (status = 415, description = "The body isn't JSON"),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 403, description = "Missing or incorrect `X-CSRF-Token`"),
+        (status = 404, description = "Note doesn't exist or the user can't access it"),
+    ),
+    security(("session" = []))
+)]
+async fn add_reaction(
+    State(state): State<AppState>,
+    Path(note_id): Path<i32>,
+    jar: CookieJar,
+    Json(payload): Json<ReactionPayload>,
+) -> Result<Json<Vec<Reaction>>, AppError> {
+    payload.validate()?;
+
+    // serializable so two concurrent requests can't both see the reaction as
+    // missing and insert it twice
+    let pool = state.pool.clone();
+    let cache = state.recipe_cache.clone();
+    let (changed, reactions) = with_transaction(&pool, IsolationLevel::Serializable, move |conn| {
+        Box::pin(async move {
+            let user_id = authenticate(conn, &state, &jar).await?;
+
+            let recipe_id = accessible_note_recipe(conn, user_id, note_id)
+                .await?
+                .ok_or(AppError::NotFound("note not found"))?;
+
+            let now_utc = Utc::now();
+            let added = conn
+                .execute(
+                    queries::ADD_REACTION,
+                    &[&payload.emoji, &user_id, &note_id, &now_utc],
+                )
+                .timed("add_reaction")
+                .await?;
+            if added > 0 {
+                notify::publish(conn, recipe_id, ChangeKind::ReactionAdded).await?;
+            }
+
+            let reactions = note_reactions(conn, note_id).await?;
+            Ok(((added > 0).then_some(recipe_id), reactions))
+        })
+    })
+    .await?;
+    // after the commit, or a read in between could cache the old reactions
+    // again
+    if let Some(recipe_id) = changed {
+        invalidate_recipe(cache.as_ref(), recipe_id);
+    }
+    Ok(Json(reactions))
+}
+
+/// Remove a reaction from a note
+///
+/// Removing a reaction the user doesn't have is a no-op.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/notes/{id}/reactions",
+    params(("id" = i32, Path, description = "Note id")),
+    request_body = ReactionPayload,
+    responses(
+        (status = 200, description = "All the reactions on the note", body = [Reaction]),
+        (status = 400, description = "The emoji isn't a single character, or there's no body"),
+        (status = 422, description = "The body doesn't deserialize, the message names the field"),
+        (status = 415, description = "The body isn't JSON"),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 403, description = "Missing or incorrect `X-CSRF-Token`"),
+        (status = 404, description = "Note doesn't exist or the user can't access it"),
+    ),
+    security(("session" = []))
+)]
+async fn remove_reaction(
+    State(state): State<AppState>,
+    Path(note_id): Path<i32>,
+    jar: CookieJar,
+    Json(payload): Json<ReactionPayload>,
+) -> Result<Json<Vec<Reaction>>, AppError> {
+    payload.validate()?;
+
+    let pool = state.pool.clone();
+    let cache = state.recipe_cache.clone();
+    let (changed, reactions) =
+        with_transaction(&pool, IsolationLevel::ReadCommitted, move |conn| {
+            Box::pin(async move {
+                let user_id = authenticate(conn, &state, &jar).await?;
+
+                let recipe_id = accessible_note_recipe(conn, user_id, note_id)
+                    .await?
+                    .ok_or(AppError::NotFound("note not found"))?;
+
+                let removed = conn
+                    .execute(
+                        queries::REMOVE_REACTION,
+                        &[&payload.emoji, &user_id, &note_id],
+                    )
+                    .timed("remove_reaction")
+                    .await?;
+                if removed > 0 {
+                    notify::publish(conn, recipe_id, ChangeKind::ReactionRemoved).await?;
+                }
+
+                let reactions = note_reactions(conn, note_id).await?;
+                Ok(((removed > 0).then_some(recipe_id), reactions))
+            })
+        })
+        .await?;
+    if let Some(recipe_id) = changed {
+        invalidate_recipe(cache.as_ref(), recipe_id);
+    }
+    Ok(Json(reactions))
+}
+
+/// Toggle a reaction on a note
+///
+/// Removes the user's reaction with the emoji if they have one, otherwise adds
+/// it.
+#[utoipa::path(
+    post,
+    path = "/api/v1/notes/{id}/react",
+    params(("id" = i32, Path, description = "Note id")),
+    request_body = ReactionPayload,
+    responses(
+        (status = 200, description = "The note's reactions by emoji", body = [ReactionSummary]),
+        (status = 400, description = "The emoji isn't a single character, or there's no body"),
+        (status = 422, description = "The body doesn't deserialize, the message names the field"),
+        (status = 415, description = "The body isn't JSON"),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+        (status = 403, description = "Missing or incorrect `X-CSRF-Token`"),
+        (status = 404, description = "Note doesn't exist or the user can't access it"),
+    ),
+    security(("session" = []))
+)]
+async fn toggle_reaction(
+    State(state): State<AppState>,
+    Path(note_id): Path<i32>,
+    jar: CookieJar,
+    Json(payload): Json<ReactionPayload>,
+) -> Result<Json<Vec<ReactionSummary>>, AppError> {
+    payload.validate()?;
+
+    // serializable for the same reason as `add_reaction`, two toggles racing
+    // could otherwise both insert
+    let pool = state.pool.clone();
+    let cache = state.recipe_cache.clone();
+    let (recipe_id, summary) = with_transaction(&pool, IsolationLevel::Serializable, move |conn| {
+        Box::pin(async move {
+            let user_id = authenticate(conn, &state, &jar).await?;
+
+            let recipe_id = accessible_note_recipe(conn, user_id, note_id)
+                .await?
+                .ok_or(AppError::NotFound("note not found"))?;
+
+            let now_utc = Utc::now();
+            // only inserts if there was nothing to delete
+            let added = conn
+                .execute(
+                    queries::TOGGLE_REACTION,
+                    &[&payload.emoji, &user_id, &note_id, &now_utc],
+                )
+                .timed("toggle_reaction")
+                .await?;
+            let kind = if added > 0 {
+                ChangeKind::ReactionAdded
+            } else {
+                ChangeKind::ReactionRemoved
+            };
+            notify::publish(conn, recipe_id, kind).await?;
+
+            let reactions = note_reactions(conn, note_id).await?;
+            Ok((recipe_id, summarize_reactions(&reactions, user_id)))
+        })
+    })
+    .await?;
+    invalidate_recipe(cache.as_ref(), recipe_id);
+    Ok(Json(summary))
+}
+
+async fn note_reactions(conn: &PgConnection, note_id: i32) -> Result<Vec<Reaction>, AppError> {
+    let reaction_rows = conn
+        .query(queries::NOTE_REACTIONS, &[&note_id])
+        .timed("note_reactions")
+        .await?;
+
+    reaction_rows.iter().map(Reaction::from_row).collect()
+}
+
+#[derive(Serialize, ToSchema)]
+struct CsrfToken {
+    csrf_token: String,
+}
+
+/// The CSRF token for the session
+///
+/// POST, PATCH and DELETE requests that authenticate with the session cookie
+/// need it as the `X-CSRF-Token` header, or they're refused with a 403. It
+/// stays the same for the life of the session, so fetch it once after
+/// logging in.
+#[utoipa::path(
+    get,
+    path = "/api/v1/csrf",
+    responses(
+        (status = 200, description = "The token", body = CsrfToken),
+        (status = 401, description = "Missing, unknown or expired session, told apart by `code`"),
+    ),
+    security(("session" = []))
+)]
+async fn csrf_token(
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> Result<Json<CsrfToken>, AppError> {
+    authenticate_primary(&state, &jar).await?;
+    let session_key = state.session_cookies.session_key(&jar)?;
+    Ok(Json(CsrfToken {
+        csrf_token: state.csrf.token(&session_key),
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+struct Version {
+    version: &'static str,
+    git_sha: &'static str,
+    built_at: chrono::DateTime<Utc>,
+}
+
+/// Build metadata for the running server
+#[utoipa::path(
+    get,
+    path = "/version",
+    responses((status = 200, description = "Build metadata", body = Version))
+)]
+async fn version() -> Json<Version> {
+    let built_at = env!("BUILD_TIMESTAMP").parse().unwrap();
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("GIT_SHA"),
+        built_at: Utc.timestamp_opt(built_at, 0).unwrap(),
+    })
+}
+
+#[derive(Serialize)]
+struct PoolState {
+    connections: u32,
+    idle_connections: u32,
+    in_use: u32,
+}
+
+impl From<bb8::State> for PoolState {
+    fn from(state: bb8::State) -> Self {
+        PoolState {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            in_use: state.connections - state.idle_connections,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PoolsState {
+    primary: PoolState,
+    replica: Option<PoolState>,
+}
+
+/// How busy the pools are, for spotting starvation during a benchmark. A 404
+/// unless `DEBUG_ENDPOINTS=1`. Not behind a session since looking one up
+/// would take a connection itself.
+async fn debug_pool(State(state): State<AppState>, method: Method, uri: Uri) -> Response<BoxBody> {
+    if !state.config.debug_endpoints {
+        return not_found(method, uri).await.into_response();
+    }
+    Json(PoolsState {
+        primary: state.pool.state().into(),
+        replica: state
+            .read_pool
+            .replica
+            .as_ref()
+            .map(|replica| replica.state().into()),
+    })
+    .into_response()
+}
+
+async fn build_pool(
+    dsn: String,
+    connector: MakeTlsConnector,
+    settings: PoolSettings,
+) -> ConnectionPool {
+    let manager = PostgresConnectionManager::new_from_stringlike(dsn, connector)
+        .expect("setup conn manager, whatever that is");
+    Pool::builder()
+        .max_size(settings.max_size)
+        .min_idle(settings.min_idle)
+        .test_on_check_out(settings.check_on_checkout)
+        .connection_timeout(settings.connection_timeout)
+        .idle_timeout(settings.idle_timeout)
+        .max_lifetime(settings.max_lifetime)
+        .build(PgManager::new(
+            manager,
+            settings.statement_timeout,
+            settings.slow_query,
+        ))
+        .await
+        .expect("created pool successfully")
+}
+
+async fn get_conn(pool: &ConnectionPool) -> Result<PooledConnection<'_, PgManager>, AppError> {
+    tracing::debug!("getting conn...");
+
+    // one more go after a dropped connection in case the database is
+    // restarting. Not after a pool timeout, that's already waited the whole
+    // connection timeout, or an error that will just happen again like bad
+    // credentials.
+    let conn = match pool.get().await {
+        Err(bb8::RunError::User(err)) if db::is_transient(&err) => {
+            tracing::warn!(%err, "couldn't get a connection, retrying");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            pool.get().await?
+        }
+        result => result?,
+    };
+
+    tracing::debug!("conn done");
+
+    Ok(conn)
+}
+
+/// Check out a connection and run `f` inside a transaction, committing if it
+/// succeeds and rolling back if it fails.
+///
+/// `f` gets a connection borrowed for an unnamed lifetime, so anything it uses
+/// from the handler has to be moved in.
+async fn with_transaction<T, F>(
+    pool: &ConnectionPool,
+    isolation: IsolationLevel,
+    f: F,
+) -> Result<T, AppError>
+where
+    F: for<'c> FnOnce(&'c PgConnection) -> BoxFuture<'c, Result<T, AppError>>,
+{
+    let conn = get_conn(pool).await?;
+    in_transaction(&conn, isolation, f).await
+}
+
+/// [`with_transaction`] on a connection that's already checked out.
+async fn in_transaction<T, F>(
+    conn: &PgConnection,
+    isolation: IsolationLevel,
+    f: F,
+) -> Result<T, AppError>
+where
+    F: for<'c> FnOnce(&'c PgConnection) -> BoxFuture<'c, Result<T, AppError>>,
+{
+    conn.begin(isolation).await?;
+    match f(conn).await {
+        Ok(value) => {
+            conn.commit().await?;
+            Ok(value)
+        }
+        Err(err) => {
+            if let Err(rollback_err) = conn.rollback().await {
+                tracing::error!(error = %rollback_err, "failed to roll back transaction");
+            }
+            Err(err)
+        }
+    }
+}
+
+/// [`authenticate`] on a primary connection of its own, for handlers that run
+/// the rest of their queries on the [`ReadPool`].
+async fn authenticate_primary(state: &AppState, jar: &CookieJar) -> Result<i32, AppError> {
+    // skip the connection entirely when the session is cached
+    if let Some(user_id) = cached_session(state, jar) {
+        return Ok(user_id);
+    }
+    // no connection for a missing cookie, or a signed one that doesn't verify
+    state.session_cookies.session_key(jar)?;
+    // a read on a fresh connection each time, so safe to retry. Not through
+    // `get_conn` since that would retry on its own as well, and not after a
+    // pool timeout which has already waited the whole connection timeout.
+    let retryable = |err: &AppError| !matches!(err, AppError::PoolTimeout) && err.is_unavailable();
+    db::retry(state.config.db_max_retries, retryable, || async {
+        let conn = state.pool.get().await.map_err(|err| {
+            tracing::warn!(%err, "couldn't get a connection");
+            AppError::from(err)
+        })?;
+        authenticate(&conn, state, jar).await
+    })
+    .await
+}
+
+fn cached_session(state: &AppState, jar: &CookieJar) -> Option<i32> {
+    let session_id = state.session_cookies.session_key(jar).ok()?;
+    let session = state
+        .sessions
+        .as_ref()?
+        .get(&session_id)
+        .filter(|session| session.expire_date > Utc::now())?;
+    Span::current().record("user_id", session.user_id);
+    state.session_cookies.touch(&session_id);
+    Some(session.user_id)
+}
+
+/// Resolve the `sessionid` cookie to a user id, from the [`SessionCache`] if
+/// it's there.
+async fn authenticate(
+    conn: &PgConnection,
+    state: &AppState,
+    jar: &CookieJar,
+) -> Result<i32, AppError> {
+    if let Some(user_id) = cached_session(state, jar) {
+        return Ok(user_id);
+    }
+    let session_id = state.session_cookies.session_key(jar)?;
+
+    let now_utc = Utc::now();
+
+    // expired sessions still match so they can be told apart from made up
+    // ones
+    let session = conn
+        .query_opt(
+            queries::SESSION,
+            // hit    |                            ^^^^^^^ expected `&dyn ToSql + Sync`, found struct `chrono::DateTime<Utc>`
+            // needed to add features = ["with-chrono-0_4"]
+            &[&session_id, &now_utc],
+        )
+        .timed("session")
+        .await?
+        .ok_or(AppError::SessionInvalid)?;
+
+    if !session.column("active")? {
+        return Err(AppError::SessionExpired);
+    }
+    let user_id: i32 = session.column("user_id")?;
+    Span::current().record("user_id", user_id);
+    state.session_cookies.touch(&session_id);
+    if let Some(sessions) = &state.sessions {
+        let expire_date = session.column("expire_date")?;
+        sessions
+            .insert(
+                session_id,
+                CachedSession {
+                    user_id,
+                    expire_date,
+                },
+            )
+            .await;
+    }
+    Ok(user_id)
+}
+
+/// Whether the recipe exists at all, regardless of who owns it.
+async fn recipe_exists(conn: &PgConnection, recipe_id: i32) -> Result<bool, AppError> {
+    let row = conn
+        .query_opt(queries::RECIPE_EXISTS, &[&recipe_id])
+        .timed("recipe_exists")
+        .await?;
+    Ok(row.is_some())
+}
+
+/// Same ownership rules as the recipe queries, without fetching anything.
+async fn can_access_recipe(
+    conn: &PgConnection,
+    user_id: i32,
+    recipe_id: i32,
+) -> Result<bool, AppError> {
+    let row = conn
+        .query_opt(queries::RECIPE_ACCESS, &[&user_id, &recipe_id])
+        .timed("recipe_access")
+        .await?;
+    Ok(row.is_some())
+}
+
+/// The recipe the note belongs to, or `None` if the note doesn't exist or the
+/// user can't access it. A note is accessible if its recipe is.
+async fn accessible_note_recipe(
+    conn: &PgConnection,
+    user_id: i32,
+    note_id: i32,
+) -> Result<Option<i32>, AppError> {
+    let note = conn
+        .query_opt(queries::NOTE_RECIPE, &[&note_id])
+        .timed("note_recipe")
+        .await?;
+    match note {
+        Some(note) => {
+            let recipe_id = note.column("recipe_id")?;
+            Span::current().record("recipe_id", recipe_id);
+            Ok(can_access_recipe(conn, user_id, recipe_id)
+                .await?
+                .then_some(recipe_id))
+        }
+        None => Ok(None),
+    }
+}
+
+struct RecipeVersion {
+    etag: String,
+    /// Doesn't move when a reaction is removed since those rows are deleted,
+    /// the ETag does catch that.
+    last_modified: chrono::DateTime<Utc>,
+}
+
+/// Compute a weak ETag and `Last-Modified` for the recipe bundle, or `None` if
+/// the recipe doesn't exist or the user can't access it. Each `include` gets
+/// its own ETag since they're different representations.
+///
+/// The recipe row and each of its child tables are checked separately, the
+/// latest `modified` for edits and the count to catch deletes.
+async fn recipe_version(
+    conn: &PgConnection,
+    user_id: i32,
+    recipe_id: i32,
+    include: Include,
+) -> Result<Option<RecipeVersion>, AppError> {
+    let row = conn
+        .query_opt(queries::RECIPE_VERSION, &[&user_id, &recipe_id])
+        .timed("etag")
+        .await?;
+
+    row.map(|row| {
+        let mut hasher = DefaultHasher::new();
+        // the bundle is tailored to the user, e.g. `ReactionSummary::reacted`
+        user_id.hash(&mut hasher);
+        include.hash(&mut hasher);
+        let timestamps = [
+            "modified",
+            "notes_modified",
+            "reactions_modified",
+            "timeline_created",
+            "ingredients_modified",
+            "sections_modified",
+            "steps_modified",
+        ]
+        .map(|column| row.column::<Option<chrono::DateTime<Utc>>>(column))
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+        for ts in &timestamps {
+            ts.map(|ts| ts.timestamp_micros()).hash(&mut hasher);
+        }
+        for column in [
+            "notes_count",
+            "reactions_count",
+            "timeline_count",
+            "ingredients_count",
+            "sections_count",
+            "steps_count",
+        ] {
+            row.column::<i64>(column)?.hash(&mut hasher);
+        }
+        let id: i32 = row.column("id")?;
+        Ok(RecipeVersion {
+            etag: format!(r#"W/"{}-{:x}""#, id, hasher.finish()),
+            last_modified: timestamps.into_iter().flatten().max().unwrap_or_default(),
+        })
+    })
+    .transpose()
+}
+
+/// Format a timestamp for `Last-Modified` and friends, e.g.
+/// `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(ts: chrono::DateTime<Utc>) -> String {
+    ts.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Weak comparison of our ETag against an `If-None-Match` header value.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// The recipes the user can access, newest first, starting after `cursor`
+/// when there is one.
+async fn recipe_rows(
+    conn: &PgConnection,
+    user_id: i32,
+    limit: i64,
+    offset: i64,
+    cursor: Option<&Cursor>,
+) -> Result<Vec<Row>, AppError> {
+    let after_created = cursor.map(|cursor| cursor.created);
+    let after_id = cursor.map(|cursor| cursor.id);
+    let recipes = conn
+        .query(
+            queries::RECIPES,
+            &[&user_id, &limit, &offset, &after_created, &after_id],
+        )
+        .timed("recipes")
+        .await?;
+    Ok(recipes)
+}
+
+/// The recipe, or `None` if it doesn't exist or the user can't access it.
+async fn recipe_row(
+    conn: &PgConnection,
+    user_id: i32,
+    recipe_id: i32,
+) -> Result<Option<Row>, AppError> {
+    Ok(recipe_rows_by_id(conn, user_id, &[recipe_id]).await?.pop())
+}
+
+/// The recipes out of `recipe_ids` that the user can access, in no particular
+/// order.
+async fn recipe_rows_by_id(
+    conn: &PgConnection,
+    user_id: i32,
+    recipe_ids: &[i32],
+) -> Result<Vec<Row>, AppError> {
+    let recipes = conn
+        .query(queries::RECIPES_BY_ID, &[&user_id, &recipe_ids])
+        .timed("recipe")
+        .await?;
+    Ok(recipes)
+}
+
+async fn load_recipe(
+    conn: &PgConnection,
+    recipe: &Row,
+    user_id: i32,
+    include: Include,
+) -> Result<Recipe, AppError> {
+    let mut recipes = load_recipes(conn, std::slice::from_ref(recipe), user_id, include).await?;
+    Ok(recipes.remove(0))
+}
+
+/// Fetch the ingredients, steps, notes, etc. for all the `recipes` in one go
+/// and assemble the bundles, in the same order. Anything not in `include` is
+/// skipped, query and all.
+///
+/// The queries run one after another on the same connection so callers can
+/// wrap them in a repeatable read transaction for a consistent view. Running
+/// them concurrently would be faster but needs a connection per query, and
+/// separate connections can't share a snapshot.
+async fn load_recipes(
+    conn: &PgConnection,
+    recipes: &[Row],
+    user_id: i32,
+    include: Include,
+) -> Result<Vec<Recipe>, AppError> {
+    let recipe_ids: Vec<i32> = recipes
+        .iter()
+        .map(|r| r.column("id"))
+        .collect::<Result<_, _>>()?;
+
+    let mut ingredients = if include.ingredients {
+        recipe_ingredient_likes(conn, &recipe_ids).await?
+    } else {
+        HashMap::new()
+    };
+
+    let mut steps = if include.steps {
+        recipe_steps(conn, &recipe_ids).await?
+    } else {
+        HashMap::new()
+    };
+
+    let mut timeline: HashMap<i32, Vec<TimelineLike>> = HashMap::new();
+    if include.timeline {
+        for (recipe_id, events) in recipe_timeline_events(conn, &recipe_ids, None).await? {
+            timeline
+                .entry(recipe_id)
+                .or_default()
+                .extend(events.into_iter().map(TimelineLike::TimelineEvent));
+        }
+    }
+    if include.notes {
+        let notes = recipe_notes(conn, &recipe_ids, user_id, include.reactions, None).await?;
+        for (recipe_id, notes) in notes {
+            timeline
+                .entry(recipe_id)
+                .or_default()
+                .extend(notes.into_iter().map(TimelineLike::Note));
+        }
+    }
+    // each kind comes back newest first, merge them the same way
+    for timeline in timeline.values_mut() {
+        timeline.sort_by_key(|entry| std::cmp::Reverse(entry.key()));
+    }
+
+    recipes
+        .iter()
+        .map(|recipe| {
+            let summary = RecipeSummary::from_row(recipe)?;
+            let id = summary.id;
+            Ok(Recipe {
+                summary,
+                owner: Owner::from_row(recipe)?,
+                ingredients: include
+                    .ingredients
+                    .then(|| ingredients.remove(&id).unwrap_or_default()),
+                steps: include.steps.then(|| steps.remove(&id).unwrap_or_default()),
+                timeline: (include.timeline || include.notes)
+                    .then(|| timeline.remove(&id).unwrap_or_default()),
+                etag: None,
+            })
+        })
+        .collect()
+}
+
+/// The ingredients of each of the recipes, by recipe id.
+///
+/// The positions are strings so Postgres sorts them by its collation, `"10"`
+/// before `"2"` and not necessarily byte by byte. The relation fetchers
+/// re-sort each recipe's rows with [`parse::fractional_index_cmp`], and the
+/// sort is stable so the SQL order only breaks ties.
+async fn recipe_ingredients(
+    conn: &PgConnection,
+    recipe_ids: &[i32],
+) -> Result<HashMap<i32, Vec<Ingredient>>, AppError> {
+    let rows = conn
+        .query(queries::INGREDIENTS, &[&recipe_ids])
+        .timed("ingredients")
+        .await?;
+    let mut ingredients: HashMap<i32, Vec<Ingredient>> = HashMap::new();
+    for i in rows {
+        ingredients
+            .entry(i.column("recipe_id")?)
+            .or_default()
+            .push(Ingredient::from_row(&i)?)
+    }
+    for ingredients in ingredients.values_mut() {
+        ingredients.sort_by(|a, b| parse::fractional_index_cmp(&a.position, &b.position));
+    }
+    Ok(ingredients)
+}
+
+/// The ingredients and section headers of each of the recipes, merged.
+async fn recipe_ingredient_likes(
+    conn: &PgConnection,
+    recipe_ids: &[i32],
+) -> Result<HashMap<i32, Vec<IngredientLike>>, AppError> {
+    let ingredients = recipe_ingredients(conn, recipe_ids).await?;
+    let sections = recipe_sections(conn, recipe_ids).await?;
+    Ok(merge_ingredients(ingredients, sections))
+}
+
+fn merge_ingredients(
+    ingredients: HashMap<i32, Vec<Ingredient>>,
+    sections: HashMap<i32, Vec<Section>>,
+) -> HashMap<i32, Vec<IngredientLike>> {
+    let mut merged: HashMap<i32, Vec<IngredientLike>> = HashMap::new();
+    for (recipe_id, recipe_ingredients) in ingredients {
+        merged.entry(recipe_id).or_default().extend(
+            recipe_ingredients
+                .into_iter()
+                .map(IngredientLike::Ingredient),
+        );
+    }
+    for (recipe_id, sections) in sections {
+        merged
+            .entry(recipe_id)
+            .or_default()
+            .extend(sections.into_iter().map(IngredientLike::Section));
+    }
+    // the section headers go between the ingredients they're positioned
+    // between, the two share one ordering
+    for ingredients in merged.values_mut() {
+        ingredients.sort_by(|a, b| parse::fractional_index_cmp(a.position(), b.position()));
+    }
+    merged
+}
+
+async fn recipe_sections(
+    conn: &PgConnection,
+    recipe_ids: &[i32],
+) -> Result<HashMap<i32, Vec<Section>>, AppError> {
+    let rows = conn
+        .query(queries::SECTIONS, &[&recipe_ids])
+        .timed("sections")
+        .await?;
+    let mut sections: HashMap<i32, Vec<Section>> = HashMap::new();
+    for sec in rows {
+        sections
+            .entry(sec.column("recipe_id")?)
+            .or_default()
+            .push(Section::from_row(&sec)?)
+    }
+    for sections in sections.values_mut() {
+        sections.sort_by(|a, b| parse::fractional_index_cmp(&a.position, &b.position));
+    }
+    Ok(sections)
+}
+
+async fn recipe_steps(
+    conn: &PgConnection,
+    recipe_ids: &[i32],
+) -> Result<HashMap<i32, Vec<Step>>, AppError> {
+    let rows = conn
+        .query(queries::STEPS, &[&recipe_ids])
+        .timed("steps")
+        .await?;
+    let mut steps: HashMap<i32, Vec<Step>> = HashMap::new();
+    for s in rows {
+        steps
+            .entry(s.column("recipe_id")?)
+            .or_default()
+            .push(Step::from_row(&s)?)
+    }
+    for steps in steps.values_mut() {
+        steps.sort_by(|a, b| parse::fractional_index_cmp(&a.position, &b.position));
+    }
+    Ok(steps)
+}
+
+/// The notes on each of the recipes, newest first, with their reactions if
+/// `with_reactions`. Only a page of them with `page`, which is meant for a
+/// single recipe since the limit is across all of them.
+async fn recipe_notes(
+    conn: &PgConnection,
+    recipe_ids: &[i32],
+    user_id: i32,
+    with_reactions: bool,
+    page: Option<TimelinePage>,
+) -> Result<HashMap<i32, Vec<Note>>, AppError> {
+    let after = page.and_then(|page| page.after);
+    let created = after.map(|after| after.created);
+    let kind = after.map(|after| after.kind);
+    let id = after.map(|after| after.id);
+    // a null limit is no limit
+    let limit = page.map(|page| page.limit);
+    let offset = page.map(|page| page.offset);
+    let rows = conn
+        .query(
+            queries::NOTES,
+            &[&recipe_ids, &created, &kind, &id, &limit, &offset],
+        )
+        .timed("notes")
+        .await?;
+
+    let mut reactions: HashMap<i32, Vec<Reaction>> = HashMap::new();
+    if with_reactions {
+        let reaction_rows = conn
+            .query(queries::REACTIONS, &[&recipe_ids])
+            .timed("reactions")
+            .await?;
+        for r in reaction_rows {
+            reactions
+                .entry(r.column("note_id")?)
+                .or_default()
+                .push(Reaction::from_row(&r)?);
+        }
+    }
+
+    let mut notes: HashMap<i32, Vec<Note>> = HashMap::new();
+    for n in rows {
+        let mut note = Note::from_row(&n)?;
+        if with_reactions {
+            let reactions = reactions.remove(&note.id).unwrap_or_default();
+            note.reaction_summary = Some(summarize_reactions(&reactions, user_id));
+            note.reactions = Some(reactions);
+        }
+        notes.entry(n.column("recipe_id")?).or_default().push(note)
+    }
+    Ok(notes)
+}
+
+/// The timeline events of each of the recipes, newest first, paged like
+/// [`recipe_notes`].
+async fn recipe_timeline_events(
+    conn: &PgConnection,
+    recipe_ids: &[i32],
+    page: Option<TimelinePage>,
+) -> Result<HashMap<i32, Vec<TimelineEvent>>, AppError> {
+    let after = page.and_then(|page| page.after);
+    let created = after.map(|after| after.created);
+    let kind = after.map(|after| after.kind);
+    let id = after.map(|after| after.id);
+    let limit = page.map(|page| page.limit);
+    let rows = conn
+        .query(
+            queries::TIMELINE_EVENTS,
+            &[&recipe_ids, &created, &kind, &id, &limit],
+        )
+        .timed("timeline")
+        .await?;
+    let mut events: HashMap<i32, Vec<TimelineEvent>> = HashMap::new();
+    for t in rows {
+        events
+            .entry(t.column("recipe_id")?)
+            .or_default()
+            .push(TimelineEvent::from_row(&t)?)
+    }
+    Ok(events)
+}
+
+/// Add an event to the recipe's timeline. Call it on the connection of the
+/// transaction making the change, so the event is only there if the change
+/// is.
+async fn record_timeline_event(
+    conn: &PgConnection,
+    recipe_id: i32,
+    user_id: i32,
+    action: TimelineAction,
+) -> Result<(), AppError> {
+    let now_utc = Utc::now();
+    conn.execute(
+        queries::CREATE_TIMELINE_EVENT,
+        &[&action.as_str(), &now_utc, &user_id, &recipe_id],
+    )
+    .timed("create_timeline_event")
+    .await?;
+    Ok(())
+}
+
+/// Mark the API responses as only for the user they were made for, so a
+/// shared cache never hands one user's recipes to another. `no-cache` because
+/// they change without notice, the browser has to revalidate with the ETag.
+///
+/// Nothing is negotiated on `Accept` or `Accept-Encoding` yet, whatever adds
+/// that has to add them to `Vary` too.
+async fn private_cache<B>(request: Request<B>, next: Next<B>) -> Response<BoxBody> {
+    let path = request.uri().path();
+    let user_scoped = path.starts_with("/api/v1/") || path == "/graphql";
+    let mut response = next.run(request).await;
+    if user_scoped {
+        response
+            .headers_mut()
+            .entry(header::CACHE_CONTROL)
+            .or_insert(HeaderValue::from_static("private, no-cache"));
+    }
+    response
+}
+
+/// JSON 404 for unknown routes, shaped like the panic response.
+async fn not_found(method: Method, uri: Uri) -> (StatusCode, Json<serde_json::Value>) {
+    tracing::info!(%method, path = uri.path(), "route not found");
+    (
+        StatusCode::NOT_FOUND,
+        Json(serde_json::json!({ "error": "route not found" })),
+    )
+}
+
+/// Turn a handler panic into a 500 instead of dropping the connection.
+fn handle_panic(err: Box<dyn Any + Send + 'static>) -> Response<Body> {
+    let details = err
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| err.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic payload");
+    tracing::error!(panic = details, "handler panicked");
+
+    let body = serde_json::json!({ "error": "internal server error" });
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merged_ingredients_sort_by_position() {
+        let ingredient = |id, position: &str| Ingredient {
+            id,
+            position: position.into(),
+            ..Ingredient::default()
+        };
+        let ingredients = HashMap::from([(1, vec![ingredient(1, "10"), ingredient(2, "1")])]);
+        let sections = HashMap::from([(
+            1,
+            vec![Section {
+                id: 3,
+                title: "Sauce".into(),
+                position: "2".into(),
+            }],
+        )]);
+        let merged = merge_ingredients(ingredients, sections);
+        let positions: Vec<&str> = merged[&1].iter().map(IngredientLike::position).collect();
+        assert_eq!(positions, ["1", "2", "10"]);
+    }
+
+    #[test]
+    fn merged_ingredients_stay_with_their_recipe() {
+        let ingredient = |id, position: &str| Ingredient {
+            id,
+            position: position.into(),
+            ..Ingredient::default()
+        };
+        let section = |id, position: &str| Section {
+            id,
+            title: "Sauce".into(),
+            position: position.into(),
+        };
+        let ingredients = HashMap::from([
+            (1, vec![ingredient(1, "1"), ingredient(2, "3")]),
+            (2, vec![ingredient(3, "2")]),
+        ]);
+        // recipe 3 only has a section
+        let sections = HashMap::from([
+            (1, vec![section(4, "2")]),
+            (2, vec![section(5, "1")]),
+            (3, vec![section(6, "1")]),
+        ]);
+        let merged = merge_ingredients(ingredients, sections);
+        let ids = |recipe_id| -> Vec<i32> {
+            merged[&recipe_id]
+                .iter()
+                .map(|like| match like {
+                    IngredientLike::Ingredient(ingredient) => ingredient.id,
+                    IngredientLike::Section(section) => section.id,
+                })
+                .collect()
+        };
+        assert_eq!(merged.len(), 3);
+        assert_eq!(ids(1), [1, 4, 2]);
+        assert_eq!(ids(2), [5, 3]);
+        assert_eq!(ids(3), [6]);
+    }
+
+    fn query(limit: Option<i64>, offset: Option<i64>) -> RecipeQuery {
+        RecipeQuery {
+            limit,
+            offset,
+            ..RecipeQuery::default()
+        }
+    }
+
+    #[test]
+    fn recipe_query_defaults() {
+        let params = RecipeQuery::default();
+        assert!(params.validate().is_ok());
+        assert_eq!(params.limit(1), 1);
+        assert_eq!(params.offset(), 0);
+        assert!(params
+            .include()
+            .is_ok_and(|include| include == Include::LIST));
+    }
+
+    #[test]
+    fn recipe_query_clamps_the_limit() {
+        assert_eq!(query(Some(0), None).limit(1), 1);
+        assert_eq!(query(Some(-5), None).limit(1), 1);
+        assert_eq!(query(Some(1), None).limit(1), 1);
+        assert_eq!(query(Some(100), None).limit(1), 100);
+        assert_eq!(query(Some(101), None).limit(1), 100);
+    }
+
+    #[test]
+    fn recipe_query_rejects_a_negative_offset() {
+        assert!(matches!(
+            query(None, Some(-1)).validate(),
+            Err(AppError::Unprocessable(message)) if message.starts_with("offset:")
+        ));
+        assert!(query(None, Some(0)).validate().is_ok());
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        for (secs, nanos, id) in [(1_700_000_000, 123_456_000, 42), (-86_400, 500_000, 1)] {
+            let cursor = Cursor {
+                created: Utc.timestamp_opt(secs, nanos).unwrap(),
+                id,
+            };
+            let decoded = Cursor::decode(&cursor.encode()).unwrap();
+            assert_eq!(decoded.created, cursor.created);
+            assert_eq!(decoded.id, cursor.id);
+        }
+    }
+
+    #[test]
+    fn bad_cursors_are_rejected() {
+        let encode = |raw: &str| base64::encode_config(raw, base64::URL_SAFE_NO_PAD);
+        let valid = Cursor {
+            created: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            id: 42,
+        }
+        .encode();
+        for after in [
+            "".to_string(),
+            "not a cursor!".to_string(),
+            valid[..valid.len() - 3].to_string(),
+            encode("1700000000000000"),
+            encode("yesterday:42"),
+            encode("1700000000000000:forty-two"),
+            encode(&format!("{}:42", i64::MAX)),
+        ] {
+            let params = RecipeQuery {
+                after: Some(after.clone()),
+                ..RecipeQuery::default()
+            };
+            let err = params
+                .cursor()
+                .err()
+                .unwrap_or_else(|| panic!("{:?}", after));
+            assert_eq!(
+                err.status_and_message(),
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "after: invalid cursor".into()
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn timeline_cursor_round_trips() {
+        let cursor = TimelineCursor {
+            created: Utc.timestamp_opt(1_700_000_000, 123_456_000).unwrap(),
+            kind: TimelineCursor::NOTE,
+            id: 42,
+        };
+        let decoded = TimelineCursor::decode(&cursor.encode()).unwrap();
+        assert_eq!(decoded.created, cursor.created);
+        assert_eq!(decoded.kind, cursor.kind);
+        assert_eq!(decoded.id, cursor.id);
+        // a recipe list cursor is missing the kind
+        let list = Cursor {
+            created: cursor.created,
+            id: 42,
+        };
+        assert!(TimelineCursor::decode(&list.encode()).is_none());
+    }
+
+    #[test]
+    fn timeline_after_and_before_dont_combine() {
+        let params = TimelineParams {
+            limit: None,
+            after: Some("x".into()),
+            before: Some(Utc::now()),
+        };
+        assert!(matches!(
+            params.cursor(),
+            Err(AppError::Unprocessable(message)) if message.starts_with("after:")
+        ));
+    }
+
+    /// The state the server would build from `config`, on `pool` which
+    /// doesn't have to be connected, without the caches or `LISTEN`.
+    pub(crate) fn test_state(config: Config, pool: ConnectionPool) -> AppState {
+        let session_cookies = SessionCookies::new(
+            config.session_cookie_format.clone(),
+            config.session_sliding,
+            pool.clone(),
+            config.session_cache_capacity,
+        );
+        AppState {
+            config: Arc::new(config),
+            graphql: graphql::schema(),
+            read_pool: ReadPool {
+                replica: None,
+                primary: pool.clone(),
+            },
+            pool,
+            timeline_changes: TimelineChanges::detached(),
+            recipe_cache: None,
+            sessions: None,
+            csrf: Csrf::new(b"test secret".to_vec(), session_cookies.clone()),
+            session_cookies,
+        }
+    }
+
+    /// The app without a database, enough for everything that's answered
+    /// before a handler runs a query.
+    fn test_app(routes: Router<AppState>) -> Router {
+        let config = Config::from_vars([
+            ("PG_DSN", "host=/nonexistent"),
+            ("CORS_ALLOWED_ORIGINS", "https://recipes.example.com"),
+            ("MAX_BODY_BYTES", "1024"),
+        ])
+        .unwrap();
+        let connector = MakeTlsConnector::new(TlsConnector::new().unwrap());
+        let manager =
+            PostgresConnectionManager::new_from_stringlike(&config.pg_dsn, connector).unwrap();
+        let pool = Pool::builder().build_unchecked(PgManager::new(manager, None, None));
+        app(routes, test_state(config, pool))
+    }
+
+    pub(crate) async fn send(
+        app: Router,
+        request: Request<Body>,
+    ) -> (StatusCode, HeaderMap, serde_json::Value) {
+        use tower::ServiceExt;
+        let response = app.oneshot(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        let json = serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+        (parts.status, parts.headers, json)
+    }
+
+    fn post_json(uri: &str, body: impl Into<Body>) -> Request<Body> {
+        Request::post(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.into())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn unknown_route_is_a_json_404() {
+        let request = Request::get("/api/v1/nope").body(Body::empty()).unwrap();
+        let (status, _, body) = send(test_app(routes()), request).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn panic_is_a_json_500() {
+        async fn boom() -> &'static str {
+            panic!("boom")
+        }
+        let app = test_app(routes().route("/boom", get(boom)));
+        let request = Request::get("/boom").body(Body::empty()).unwrap();
+        let (status, headers, body) = send(app, request).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        assert_eq!(
+            body,
+            serde_json::json!({ "error": "internal server error" })
+        );
+    }
+
+    #[tokio::test]
+    async fn post_without_csrf_token_is_rejected() {
+        let request = Request::post("/api/v1/recipes/1/archive")
+            .header(header::COOKIE, "sessionid=somesessionkey")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _, body) = send(test_app(routes()), request).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"], "CSRF token missing or incorrect");
+    }
+
+    #[tokio::test]
+    async fn authorization_header_doesnt_skip_csrf() {
+        let request = Request::post("/api/v1/recipes/1/archive")
+            .header(header::COOKIE, "sessionid=somesessionkey")
+            .header(header::AUTHORIZATION, "Bearer junk")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _, _) = send(test_app(routes()), request).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn body_over_the_limit_is_a_413() {
+        let text = "a".repeat(2048);
+        let request = post_json(
+            "/api/v1/recipes/1/notes",
+            serde_json::json!({ "text": text }).to_string(),
+        );
+        let (status, _, body) = send(test_app(routes()), request).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(body["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn body_that_isnt_json_is_a_415() {
+        let request = Request::post("/api/v1/recipes/1/notes")
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(Body::from("hello"))
+            .unwrap();
+        let (status, _, body) = send(test_app(routes()), request).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert!(body["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn body_that_doesnt_deserialize_is_a_422() {
+        let request = post_json("/api/v1/recipes/1/notes", r#"{"text": 5}"#);
+        let (status, _, body) = send(test_app(routes()), request).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body["error"].as_str().unwrap().starts_with("text"));
+    }
+
+    #[tokio::test]
+    async fn body_with_trailing_data_is_a_422() {
+        for trailing in [r#"{"text": "x"} garbage"#, r#"{"text": "x"}{}"#] {
+            let request = post_json("/api/v1/recipes/1/notes", trailing);
+            let (status, _, body) = send(test_app(routes()), request).await;
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", trailing);
+            assert!(body["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("trailing characters"));
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_body_is_a_400() {
+        let request = post_json("/api/v1/recipes/1/notes", Body::empty());
+        let (status, _, _) = send(test_app(routes()), request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn query_that_doesnt_parse_is_a_422() {
+        let request = Request::get("/api/v1/recipes?limit=lots")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _, body) = send(test_app(routes()), request).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body["error"].as_str().unwrap().starts_with("limit"));
+    }
+
+    #[tokio::test]
+    async fn missing_session_is_a_401_without_the_database() {
+        let request = Request::get("/api/v1/recipes").body(Body::empty()).unwrap();
+        let (status, _, body) = send(test_app(routes()), request).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["code"], "session_missing");
+    }
+
+    #[tokio::test]
+    async fn unavailable_database_is_a_503_with_retry_after() {
+        let config =
+            Config::from_vars([("PG_DSN", "host=/nonexistent"), ("RETRY_AFTER_SECS", "7")])
+                .unwrap();
+        let connector = MakeTlsConnector::new(TlsConnector::new().unwrap());
+        let manager =
+            PostgresConnectionManager::new_from_stringlike(&config.pg_dsn, connector).unwrap();
+        let pool = Pool::builder()
+            .connection_timeout(Duration::from_millis(100))
+            .build_unchecked(PgManager::new(manager, None, None));
+        let request = Request::get("/api/v1/tags")
+            .header(header::COOKIE, "sessionid=somesessionkey")
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, _) = send(app(routes(), test_state(config, pool)), request).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(headers[header::RETRY_AFTER], "7");
+    }
+
+    #[tokio::test]
+    async fn cors_preflight() {
+        let request = Request::options("/api/v1/recipes/1/notes")
+            .header(header::ORIGIN, "https://recipes.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "x-csrf-token")
+            .body(Body::empty())
+            .unwrap();
+        let (status, headers, _) = send(test_app(routes()), request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://recipes.example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        let allowed = headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap();
+        assert!(allowed.contains("POST"));
+    }
+
+    #[tokio::test]
+    async fn cors_preflight_from_another_origin_isnt_allowed() {
+        let request = Request::options("/api/v1/recipes/1/notes")
+            .header(header::ORIGIN, "https://evil.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .body(Body::empty())
+            .unwrap();
+        let (_, headers, _) = send(test_app(routes()), request).await;
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn api_responses_are_private() {
+        // a 401 since there's no session, errors are user scoped too
+        let request = Request::get("/api/v1/recipes").body(Body::empty()).unwrap();
+        let (status, headers, _) = send(test_app(routes()), request).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(headers[header::CACHE_CONTROL], "private, no-cache");
+
+        let request = Request::get("/version").body(Body::empty()).unwrap();
+        let (status, headers, _) = send(test_app(routes()), request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!headers.contains_key(header::CACHE_CONTROL));
+    }
 }
@@ -0,0 +1,128 @@
+use crate::db::PgConnection;
+use futures::{stream, StreamExt};
+use postgres_native_tls::MakeTlsConnector;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio_postgres::{AsyncMessage, Error};
+
+const CHANNEL: &str = "recipe_timeline";
+
+/// Published when something in a recipe's timeline changes.
+///
+/// It only says what kind of change happened, clients refetch the recipe to
+/// see it, so nothing leaks to a stream whose user has since lost access.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TimelineChange {
+    pub recipe_id: i32,
+    pub kind: ChangeKind,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    NoteCreated,
+    ReactionAdded,
+    ReactionRemoved,
+}
+
+impl ChangeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChangeKind::NoteCreated => "note_created",
+            ChangeKind::ReactionAdded => "reaction_added",
+            ChangeKind::ReactionRemoved => "reaction_removed",
+        }
+    }
+}
+
+/// Fans out the notifications from a single `LISTEN` connection to every
+/// subscriber.
+#[derive(Clone)]
+pub struct TimelineChanges(broadcast::Sender<TimelineChange>);
+
+impl TimelineChanges {
+    pub fn subscribe(&self) -> broadcast::Receiver<TimelineChange> {
+        self.0.subscribe()
+    }
+}
+
+#[cfg(test)]
+impl TimelineChanges {
+    /// Never sees a change, for tests that don't have a database.
+    pub fn detached() -> Self {
+        TimelineChanges(broadcast::channel(1).0)
+    }
+}
+
+/// Start listening for timeline changes in the background.
+///
+/// This has to be the primary, notifications aren't replicated. The listener
+/// reconnects if the connection drops, anything published in the meantime is
+/// missed.
+pub fn listen(dsn: String, connector: MakeTlsConnector) -> TimelineChanges {
+    let (tx, _) = broadcast::channel(256);
+    let changes = TimelineChanges(tx.clone());
+    tokio::spawn(async move {
+        loop {
+            match listen_once(&dsn, connector.clone(), &tx).await {
+                Ok(()) => tracing::warn!("timeline listener disconnected"),
+                Err(err) => tracing::error!(%err, "timeline listener failed"),
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+    changes
+}
+
+async fn listen_once(
+    dsn: &str,
+    connector: MakeTlsConnector,
+    tx: &broadcast::Sender<TimelineChange>,
+) -> Result<(), Error> {
+    let (client, mut connection) = tokio_postgres::connect(dsn, connector).await?;
+
+    // the connection needs polling for the LISTEN below to go through, so drive
+    // it separately and hand the notifications back
+    let (notification_tx, mut notifications) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(notification)) => {
+                    if notification_tx.send(notification).is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    tracing::error!(%err, "timeline listener connection error");
+                    break;
+                }
+            }
+        }
+    });
+
+    client.batch_execute(&format!("LISTEN {}", CHANNEL)).await?;
+    tracing::info!("listening for timeline changes");
+
+    while let Some(notification) = notifications.recv().await {
+        match serde_json::from_str(notification.payload()) {
+            // no subscribers is fine
+            Ok(change) => drop(tx.send(change)),
+            Err(err) => tracing::warn!(%err, "bad timeline notification"),
+        }
+    }
+    Ok(())
+}
+
+/// Tell the listeners about a change. Inside a transaction the notification
+/// only goes out on commit.
+pub async fn publish(conn: &PgConnection, recipe_id: i32, kind: ChangeKind) -> Result<(), Error> {
+    let payload = serde_json::to_string(&TimelineChange { recipe_id, kind })
+        .expect("serializing a timeline change can't fail");
+    conn.execute("SELECT pg_notify($1, $2)", &[&CHANNEL, &payload])
+        .timed("notify")
+        .await?;
+    Ok(())
+}
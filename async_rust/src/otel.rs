@@ -0,0 +1,63 @@
+//! OpenTelemetry export with the `otel` feature and
+//! `OTEL_EXPORTER_OTLP_ENDPOINT`, the spans go to a collector over OTLP/gRPC
+//! as well as to the log.
+
+use axum::http::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use tracing::Span;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+pub fn layer<S>(endpoint: &str) -> OpenTelemetryLayer<S, trace::Tracer>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            env!("CARGO_PKG_NAME"),
+        )])))
+        // batched in the background so exporting is off the request path
+        .install_batch(runtime::Tokio)
+        .expect("install the OTLP exporter");
+    tracing_opentelemetry::layer().with_tracer(tracer)
+}
+
+struct Headers<'a>(&'a HeaderMap);
+
+impl Extractor for Headers<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Continue the caller's trace when the request has a `traceparent`,
+/// otherwise the request span starts a new one.
+pub fn set_parent(span: &Span, headers: &HeaderMap) {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&Headers(headers)));
+    span.set_parent(parent);
+}
+
+/// Export whatever's still batched, called once the server has stopped.
+pub async fn shutdown() {
+    // blocks until the exporter is done
+    tokio::task::spawn_blocking(global::shutdown_tracer_provider)
+        .await
+        .ok();
+}
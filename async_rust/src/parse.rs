@@ -0,0 +1,296 @@
+//! Best effort parsing of the free text recipe fields, so clients can sort
+//! and filter on them. Anything that doesn't look right is `None`.
+
+use std::cmp::Ordering;
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Word(String),
+    /// `-`, `–` or `to`
+    Range,
+    /// The `:` in `1:30`
+    Colon,
+}
+
+fn vulgar_fraction(c: char) -> Option<f64> {
+    match c {
+        '¼' => Some(0.25),
+        '½' => Some(0.5),
+        '¾' => Some(0.75),
+        '⅓' => Some(1.0 / 3.0),
+        '⅔' => Some(2.0 / 3.0),
+        _ => None,
+    }
+}
+
+fn tokens(text: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+            while let Some(&c) = chars
+                .peek()
+                .filter(|c| c.is_ascii_digit() || **c == '.' || **c == '/')
+            {
+                number.push(c);
+                chars.next();
+            }
+            let value = match number.split_once('/') {
+                Some((numerator, denominator)) => numerator
+                    .parse::<f64>()
+                    .ok()
+                    .zip(denominator.parse::<f64>().ok().filter(|d| *d != 0.0))
+                    .map(|(n, d)| n / d),
+                None => number.parse().ok(),
+            };
+            match value {
+                // `1 1/2` and `1½` are one number
+                Some(value) => match tokens.last_mut() {
+                    Some(Token::Number(whole)) if value < 1.0 && whole.fract() == 0.0 => {
+                        *whole += value
+                    }
+                    _ => tokens.push(Token::Number(value)),
+                },
+                None => tokens.push(Token::Word(number)),
+            }
+        } else if let Some(value) = vulgar_fraction(c) {
+            chars.next();
+            match tokens.last_mut() {
+                Some(Token::Number(whole)) if whole.fract() == 0.0 => *whole += value,
+                _ => tokens.push(Token::Number(value)),
+            }
+        } else if c.is_alphabetic() {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_alphabetic()) {
+                word.extend(c.to_lowercase());
+                chars.next();
+            }
+            tokens.push(if word == "to" {
+                Token::Range
+            } else {
+                Token::Word(word)
+            });
+        } else {
+            chars.next();
+            match c {
+                '-' | '–' => tokens.push(Token::Range),
+                ':' => tokens.push(Token::Colon),
+                _ => {}
+            }
+        }
+    }
+    tokens
+}
+
+fn unit_minutes(word: &str) -> Option<f64> {
+    match word {
+        "m" | "min" | "mins" | "minute" | "minutes" => Some(1.0),
+        "h" | "hr" | "hrs" | "hour" | "hours" => Some(60.0),
+        "d" | "day" | "days" => Some(24.0 * 60.0),
+        _ => None,
+    }
+}
+
+/// Total minutes in e.g. `1 hour 30 min`, `1h30m`, `1:30`, `1.5 hours` or a
+/// bare `45`. Ranges like `20-30 min` give the upper bound.
+pub fn minutes(time: &str) -> Option<i32> {
+    let mut total = 0.0;
+    let mut found = false;
+    let mut pending: Option<f64> = None;
+    for token in tokens(time) {
+        match token {
+            Token::Number(n) => {
+                // `1 hour 30` means 30 minutes, same as a trailing bare number
+                if let Some(minutes) = pending.replace(n) {
+                    total += minutes;
+                }
+                found = true;
+            }
+            Token::Word(word) => {
+                if let Some(factor) = unit_minutes(&word) {
+                    if let Some(n) = pending.take() {
+                        total += n * factor;
+                    }
+                }
+            }
+            // only keep the upper bound
+            Token::Range => {
+                total = 0.0;
+                pending = None;
+            }
+            Token::Colon => {
+                if let Some(hours) = pending.take() {
+                    total += hours * 60.0;
+                }
+            }
+        }
+    }
+    total += pending.unwrap_or_default();
+    found.then(|| total.round() as i32)
+}
+
+/// The range in e.g. `4-6`, `4 to 6 servings`, `serves 4` or `makes 12`,
+/// a single number is both ends.
+pub fn servings(servings: &str) -> Option<(i32, i32)> {
+    let tokens = tokens(servings);
+    let (start, low) = tokens
+        .iter()
+        .enumerate()
+        .find_map(|(i, token)| match token {
+            Token::Number(n) => Some((i, *n)),
+            _ => None,
+        })?;
+    let high = match tokens.get(start + 1..start + 3) {
+        Some([Token::Range, Token::Number(high)]) => *high,
+        _ => low,
+    };
+    let (low, high) = (low.round() as i32, high.round() as i32);
+    Some((low.min(high), low.max(high)))
+}
+
+/// Order `position`s the way they were generated. They're fractional indexes,
+/// keys that go byte by byte so there's always room to slot a new one in
+/// between, e.g. `"a"`, `"aa"`, `"b"` or the seed's `"0030"`, `"00305"`,
+/// `"0040"`. SQL orders by the collation instead, which can skip punctuation
+/// and fold case, hence sorting again here.
+///
+/// Plain numbers, like `"1"`, `"2"`, `"10"` in older rows, compare by value
+/// and come before the keys. A leading zero, other than in `"0.5"`, makes it
+/// a key.
+pub fn fractional_index_cmp(a: &str, b: &str) -> Ordering {
+    let number = |s: &str| {
+        let padded = s.len() > 1 && s.starts_with('0') && !s.starts_with("0.");
+        let numeric = s.bytes().all(|c| c.is_ascii_digit() || c == b'.');
+        if padded || !numeric {
+            return None;
+        }
+        s.parse::<f64>().ok()
+    };
+    match (number(a), number(b)) {
+        (Some(x), Some(y)) => x.total_cmp(&y).then_with(|| a.cmp(b)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.as_bytes().cmp(b.as_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minutes_ranges_give_the_upper_bound() {
+        assert_eq!(minutes("10-15 min"), Some(15));
+        assert_eq!(minutes("1-2 hours"), Some(120));
+    }
+
+    #[test]
+    fn minutes_hours_and_minutes() {
+        assert_eq!(minutes("1 hr 30 min"), Some(90));
+        assert_eq!(minutes("2 hours"), Some(120));
+        assert_eq!(minutes("45 minutes"), Some(45));
+    }
+
+    #[test]
+    fn minutes_bare_numbers() {
+        assert_eq!(minutes("45"), Some(45));
+        assert_eq!(minutes("0"), Some(0));
+    }
+
+    #[test]
+    fn minutes_garbage_is_none() {
+        assert_eq!(minutes(""), None);
+        assert_eq!(minutes("overnight"), None);
+        assert_eq!(minutes("a while"), None);
+    }
+
+    #[test]
+    fn minutes_messy_inputs() {
+        assert_eq!(minutes("30 min"), Some(30));
+        assert_eq!(minutes("1h30"), Some(90));
+        assert_eq!(minutes("1h30m"), Some(90));
+        assert_eq!(minutes("1:30"), Some(90));
+        assert_eq!(minutes("1.5 hours"), Some(90));
+        assert_eq!(minutes("1 1/2 hours"), Some(90));
+        assert_eq!(minutes("½ hour"), Some(30));
+        assert_eq!(minutes("20 to 30 minutes"), Some(30));
+        assert_eq!(minutes("20–30 Minutes"), Some(30));
+        assert_eq!(minutes("1 day"), Some(24 * 60));
+        assert_eq!(minutes("about 40 mins"), Some(40));
+    }
+
+    #[test]
+    fn servings_ranges() {
+        assert_eq!(servings("4-6"), Some((4, 6)));
+        assert_eq!(servings("4 to 6 servings"), Some((4, 6)));
+        // backwards ranges come out in order
+        assert_eq!(servings("6-4"), Some((4, 6)));
+    }
+
+    #[test]
+    fn servings_single_values() {
+        assert_eq!(servings("4"), Some((4, 4)));
+        assert_eq!(servings("serves 4"), Some((4, 4)));
+        assert_eq!(servings("makes 12"), Some((12, 12)));
+        assert_eq!(servings("Serves 4 (as a side)"), Some((4, 4)));
+        assert_eq!(servings("2½"), Some((3, 3)));
+    }
+
+    #[test]
+    fn servings_garbage_is_none() {
+        assert_eq!(servings(""), None);
+        assert_eq!(servings("a crowd"), None);
+    }
+
+    #[test]
+    fn fractional_index_keys_compare_bytewise() {
+        assert_eq!(fractional_index_cmp("a", "aa"), Ordering::Less);
+        assert_eq!(fractional_index_cmp("aa", "b"), Ordering::Less);
+        assert_eq!(fractional_index_cmp("a19", "a2"), Ordering::Less);
+        // uppercase sorts before lowercase, no case folding
+        assert_eq!(fractional_index_cmp("Zz", "a"), Ordering::Less);
+    }
+
+    #[test]
+    fn fractional_index_numbers_compare_by_value() {
+        assert_eq!(fractional_index_cmp("1", "2"), Ordering::Less);
+        assert_eq!(fractional_index_cmp("2", "10"), Ordering::Less);
+        assert_eq!(fractional_index_cmp("1.5", "2"), Ordering::Less);
+        assert_eq!(fractional_index_cmp("0.5", "1"), Ordering::Less);
+    }
+
+    #[test]
+    fn fractional_index_numbers_before_keys() {
+        assert_eq!(fractional_index_cmp("10", "a"), Ordering::Less);
+        assert_eq!(fractional_index_cmp("10", "1a"), Ordering::Less);
+        // zero padded, so a key
+        assert_eq!(fractional_index_cmp("10", "0030"), Ordering::Less);
+        assert_eq!(fractional_index_cmp("a", "2"), Ordering::Greater);
+    }
+
+    #[test]
+    fn fractional_index_equal_keys() {
+        assert_eq!(fractional_index_cmp("a", "a"), Ordering::Equal);
+        assert_eq!(fractional_index_cmp("10", "10"), Ordering::Equal);
+        assert_eq!(fractional_index_cmp("0030", "0030"), Ordering::Equal);
+        // same value, still a total order
+        assert_eq!(fractional_index_cmp("1.5", "1.50"), Ordering::Less);
+    }
+
+    #[test]
+    fn fractional_index_sorts_positions() {
+        let mut positions = vec!["10", "2", "1"];
+        positions.sort_by(|a, b| fractional_index_cmp(a, b));
+        assert_eq!(positions, ["1", "2", "10"]);
+
+        // the seed's keys, with sections slotted in between
+        let mut positions = vec!["0040", "00305", "0000", "0030", "00105", "0010"];
+        positions.sort_by(|a, b| fractional_index_cmp(a, b));
+        assert_eq!(
+            positions,
+            ["0000", "0010", "00105", "0030", "00305", "0040"]
+        );
+    }
+}
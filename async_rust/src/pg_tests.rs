@@ -0,0 +1,515 @@
+//! Tests against a real Postgres, for what the router tests in `main.rs`
+//! can't reach without one. They're behind the `pg-tests` feature and need
+//! `TEST_PG_DSN`, a role that can create databases, e.g.
+//!
+//! ```sh
+//! docker run --rm -e POSTGRES_HOST_AUTH_METHOD=trust -p 5432:5432 postgres:15
+//! TEST_PG_DSN="host=localhost user=postgres sslmode=disable" cargo test --features pg-tests
+//! ```
+//!
+//! Each test gets a database of its own, loaded with `tests/fixtures` and
+//! dropped again when the test is done, so they can run in parallel.
+
+use super::*;
+use crate::tests::{send, test_state};
+use std::sync::atomic::{AtomicU32, Ordering};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::Client;
+
+const SCHEMA: &str = include_str!("../tests/fixtures/schema.sql");
+const SEED: &str = include_str!("../tests/fixtures/seed.sql");
+
+// seeded sessions, see `seed.sql`
+const ALICE: &str = "sessionid=alicesession";
+const CAROL: &str = "sessionid=carolsession";
+
+static DATABASES: AtomicU32 = AtomicU32::new(0);
+
+fn connector() -> MakeTlsConnector {
+    MakeTlsConnector::new(TlsConnector::new().unwrap())
+}
+
+async fn connect(config: &tokio_postgres::Config) -> Client {
+    let (client, connection) = config.connect(connector()).await.unwrap();
+    tokio::spawn(connection);
+    client
+}
+
+/// A seeded database, dropped with the value.
+struct TestDb {
+    /// `TEST_PG_DSN`, where the database is created and dropped from
+    admin: tokio_postgres::Config,
+    name: String,
+}
+
+impl TestDb {
+    async fn new() -> TestDb {
+        let dsn = env::var("TEST_PG_DSN").expect("the pg-tests need TEST_PG_DSN");
+        let admin: tokio_postgres::Config = dsn.parse().expect("TEST_PG_DSN doesn't parse");
+        let name = format!(
+            "recipeyak_test_{}_{}",
+            std::process::id(),
+            DATABASES.fetch_add(1, Ordering::Relaxed)
+        );
+        connect(&admin)
+            .await
+            .batch_execute(&format!(r#"CREATE DATABASE "{}""#, name))
+            .await
+            .unwrap();
+        let db = TestDb { admin, name };
+        let client = db.connect().await;
+        client.batch_execute(SCHEMA).await.unwrap();
+        client.batch_execute(SEED).await.unwrap();
+        db
+    }
+
+    fn config(&self) -> tokio_postgres::Config {
+        let mut config = self.admin.clone();
+        config.dbname(&self.name);
+        config
+    }
+
+    /// A connection of its own, for setting up or checking on a test.
+    async fn connect(&self) -> Client {
+        connect(&self.config()).await
+    }
+
+    fn pool(
+        &self,
+        statement_timeout: Option<Duration>,
+        slow_query: Option<Duration>,
+    ) -> ConnectionPool {
+        let manager = PostgresConnectionManager::new(self.config(), connector());
+        Pool::builder().build_unchecked(PgManager::new(manager, statement_timeout, slow_query))
+    }
+
+    /// The state the server would have with just the required settings, the
+    /// caches are left off.
+    fn state(&self) -> AppState {
+        // the pool is built from the test database's config instead
+        let config = Config::from_vars([("PG_DSN", "unused")]).unwrap();
+        test_state(config, self.pool(None, None))
+    }
+
+    fn app(&self) -> Router {
+        app(routes(), self.state())
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        // `drop` can't await, and the test's runtime may be shutting down
+        // mid-panic, so this gets a runtime of its own. `FORCE` closes the
+        // pool's connections that are still open.
+        let admin = self.admin.clone();
+        let drop_database = format!(r#"DROP DATABASE IF EXISTS "{}" WITH (FORCE)"#, self.name);
+        let dropped = std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async { connect(&admin).await.batch_execute(&drop_database).await })
+        })
+        .join();
+        if !matches!(dropped, Ok(Ok(()))) {
+            eprintln!("couldn't drop {}", self.name);
+        }
+    }
+}
+
+fn get(uri: &str, cookie: &str) -> Request<Body> {
+    Request::get(uri)
+        .header(header::COOKIE, cookie)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// A JSON request as alice, with the CSRF token `state` expects.
+fn write(state: &AppState, method: Method, uri: &str, body: serde_json::Value) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::COOKIE, ALICE)
+        .header(&csrf::X_CSRF_TOKEN, state.csrf.token("alicesession"))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn recipes_list_matches_the_fixture() {
+    let db = TestDb::new().await;
+    let request = get(
+        "/api/v1/recipes?limit=10&include=ingredients,steps,timeline,notes,reactions",
+        ALICE,
+    );
+    let (status, headers, body) = send(db.app(), request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers["x-total-count"], "2");
+    let expected: serde_json::Value =
+        serde_json::from_str(include_str!("../tests/fixtures/recipes_list.json")).unwrap();
+    assert_eq!(body, expected);
+}
+
+#[tokio::test]
+async fn cached_recipe_is_loaded_once_until_it_changes() {
+    let db = TestDb::new().await;
+    let mut state = db.state();
+    state.recipe_cache = Some(
+        Cache::builder()
+            .max_capacity(10)
+            .support_invalidation_closures()
+            .build(),
+    );
+    let app = app(routes(), state.clone());
+
+    let (status, _, first) = send(app.clone(), get("/api/v1/recipes/1", ALICE)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(first["steps"][0]["text"], "Boil the pasta.");
+
+    // behind the server's back and without touching `modified`, so only a
+    // read that loads the recipe again would see it
+    db.connect()
+        .await
+        .execute(
+            r#"UPDATE "core_step" SET "text" = 'Boil the water.' WHERE "id" = 1"#,
+            &[],
+        )
+        .await
+        .unwrap();
+    let (status, _, second) = send(app.clone(), get("/api/v1/recipes/1", ALICE)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(second, first);
+
+    let react = write(
+        &state,
+        Method::POST,
+        "/api/v1/notes/1/react",
+        serde_json::json!({"emoji": "🔥"}),
+    );
+    let (status, _, _) = send(app.clone(), react).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, third) = send(app, get("/api/v1/recipes/1", ALICE)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(third["steps"][0]["text"], "Boil the water.");
+}
+
+#[tokio::test]
+async fn children_are_bucketed_by_recipe() {
+    let db = TestDb::new().await;
+    let pool = db.pool(None, None);
+    let conn = pool.get().await.unwrap();
+    let recipe_ids = [1, 2];
+
+    let ingredients = recipe_ingredient_likes(&conn, &recipe_ids).await.unwrap();
+    let ingredient_ids = |recipe_id| -> Vec<(&str, i32)> {
+        ingredients[&recipe_id]
+            .iter()
+            .map(|like| match like {
+                IngredientLike::Ingredient(ingredient) => ("ingredient", ingredient.id),
+                IngredientLike::Section(section) => ("section", section.id),
+            })
+            .collect()
+    };
+    assert_eq!(
+        ingredient_ids(1),
+        [("ingredient", 1), ("section", 1), ("ingredient", 2)]
+    );
+    assert_eq!(ingredient_ids(2), [("ingredient", 4)]);
+
+    let steps = recipe_steps(&conn, &recipe_ids).await.unwrap();
+    let step_ids =
+        |recipe_id| -> Vec<i32> { steps[&recipe_id].iter().map(|step| step.id).collect() };
+    assert_eq!(step_ids(1), [1, 2]);
+    assert_eq!(step_ids(2), [3]);
+
+    let notes = recipe_notes(&conn, &recipe_ids, 1, true, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        notes[&1].iter().map(|note| note.id).collect::<Vec<_>>(),
+        [1]
+    );
+    assert_eq!(
+        notes[&2].iter().map(|note| note.id).collect::<Vec<_>>(),
+        [2]
+    );
+    // the one seeded reaction is on recipe 1's note
+    assert_eq!(notes[&1][0].reactions.as_ref().map(Vec::len), Some(1));
+    assert_eq!(notes[&2][0].reactions.as_ref().map(Vec::len), Some(0));
+
+    let events = recipe_timeline_events(&conn, &recipe_ids, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        events[&1].iter().map(|event| event.id).collect::<Vec<_>>(),
+        [1]
+    );
+    assert_eq!(
+        events[&2].iter().map(|event| event.id).collect::<Vec<_>>(),
+        [2]
+    );
+    // recipe 3 has an event too but wasn't asked for
+    assert_eq!(events.len(), 2);
+}
+
+#[tokio::test]
+async fn expired_and_unknown_sessions_are_told_apart() {
+    let db = TestDb::new().await;
+    for (cookie, code) in [
+        ("sessionid=aliceexpired", "session_expired"),
+        ("sessionid=madeup", "session_invalid"),
+    ] {
+        let (status, _, body) = send(db.app(), get("/api/v1/recipes", cookie)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", cookie);
+        assert_eq!(body["code"], code);
+    }
+}
+
+#[tokio::test]
+async fn absent_or_mistyped_column_is_a_500() {
+    let db = TestDb::new().await;
+    let row = db
+        .connect()
+        .await
+        .query_one(r#"SELECT 1 "id""#, &[])
+        .await
+        .unwrap();
+    for err in [
+        row.column::<String>("name").unwrap_err(),
+        row.column::<String>("id").unwrap_err(),
+    ] {
+        assert!(matches!(&err, AppError::Internal(message) if message.starts_with("column ")));
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        // the column isn't named to the client
+        assert_eq!(&body[..], br#"{"error":"internal server error"}"#);
+    }
+}
+
+#[tokio::test]
+async fn query_past_the_statement_timeout_is_a_504() {
+    let db = TestDb::new().await;
+    let pool = db.pool(Some(Duration::from_millis(100)), None);
+    let conn = pool.get().await.unwrap();
+    let err = AppError::from(
+        conn.query("SELECT pg_sleep(1)", &[])
+            .await
+            .expect_err("the timeout cancels it"),
+    );
+    assert!(matches!(&err, AppError::Db(err) if err.code() == Some(&SqlState::QUERY_CANCELED)));
+    assert_eq!(err.into_response().status(), StatusCode::GATEWAY_TIMEOUT);
+}
+
+#[tokio::test]
+async fn cursor_pages_through_every_recipe_once() {
+    let db = TestDb::new().await;
+    // ties on `created`, only the id tells them apart
+    db.connect()
+        .await
+        .batch_execute(
+            r#"
+INSERT INTO "core_recipe" ("id", "name", "object_id", "content_type_id", "created", "modified")
+SELECT
+	"id", 'Tied', 1, 1, '2024-02-01T00:00:00Z', '2024-02-01T00:00:00Z'
+FROM
+	generate_series(10, 14) "id";
+"#,
+        )
+        .await
+        .unwrap();
+
+    let app = db.app();
+    let mut seen = Vec::new();
+    let first_page = "/api/v1/recipes?limit=2&envelope=true&include=&fields=id";
+    let mut uri = first_page.to_string();
+    loop {
+        let (status, _, body) = send(app.clone(), get(&uri, ALICE)).await;
+        assert_eq!(status, StatusCode::OK);
+        seen.extend(
+            body["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|r| r["id"].clone()),
+        );
+        match body["meta"]["next_cursor"].as_str() {
+            Some(cursor) => uri = format!("{}&after={}", first_page, cursor),
+            None => break,
+        }
+    }
+    assert_eq!(seen, [14, 13, 12, 11, 10, 2, 1]);
+}
+
+#[tokio::test]
+async fn timeline_pages_through_ties_once() {
+    let db = TestDb::new().await;
+    // two notes and two events on recipe 1 at the same instant, on top of the
+    // seeded note and event
+    db.connect()
+        .await
+        .batch_execute(
+            r#"
+INSERT INTO "core_note" ("id", "text", "created", "modified", "recipe_id", "created_by_id")
+SELECT
+	"id", 'Tied', '2024-03-01T00:00:00Z', '2024-03-01T00:00:00Z', 1, 1
+FROM
+	generate_series(10, 11) "id";
+INSERT INTO "timeline_event" ("id", "action", "created", "created_by_id", "recipe_id")
+SELECT
+	"id", 'edited', '2024-03-01T00:00:00Z', 1, 1
+FROM
+	generate_series(10, 11) "id";
+"#,
+        )
+        .await
+        .unwrap();
+
+    let app = db.app();
+    let mut seen = Vec::new();
+    let mut uri = "/api/v1/recipes/1/timeline?limit=1".to_string();
+    loop {
+        let (status, headers, body) = send(app.clone(), get(&uri, ALICE)).await;
+        assert_eq!(status, StatusCode::OK);
+        for entry in body.as_array().unwrap() {
+            let (kind, entry) = entry.as_object().unwrap().iter().next().unwrap();
+            seen.push((kind.clone(), entry["id"].as_i64().unwrap()));
+        }
+        let Some(link) = headers.get(header::LINK) else {
+            break;
+        };
+        let link = link.to_str().unwrap();
+        uri = link[1..link.find('>').unwrap()].to_string();
+    }
+    let note = |id| ("Note".to_string(), id);
+    let event = |id| ("TimelineEvent".to_string(), id);
+    assert_eq!(
+        seen,
+        [note(11), note(10), event(11), event(10), note(1), event(1)]
+    );
+}
+
+#[tokio::test]
+async fn recipes_list_is_deterministic() {
+    let db = TestDb::new().await;
+    let (status, _, first) = send(db.app(), get("/api/v1/recipes", ALICE)).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, _, second) = send(db.app(), get("/api/v1/recipes", ALICE)).await;
+    assert_eq!(second, first);
+    // the newest, not any one of them
+    assert_eq!(first["id"], 2);
+}
+
+#[tokio::test]
+async fn no_recipes_is_a_404() {
+    let db = TestDb::new().await;
+    for uri in ["/api/v1/recipes/random", "/api/v1/recipes"] {
+        let (status, _, body) = send(db.app(), get(uri, CAROL)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", uri);
+        assert!(body["error"].is_string());
+    }
+}
+
+#[tokio::test]
+async fn timeline_names_the_user_not_their_email() {
+    let db = TestDb::new().await;
+    // alice has a name set
+    let (status, _, timeline) = send(db.app(), get("/api/v1/recipes/1/timeline", ALICE)).await;
+    assert_eq!(status, StatusCode::OK);
+    let event = &timeline[1]["TimelineEvent"];
+    assert_eq!(event["created_by_name"], "Alice");
+    // the note is bob's, who has no name, so it stays null rather than
+    // picking one up from the query's other user join
+    let note = &timeline[0]["Note"];
+    assert_eq!(note["name"], serde_json::Value::Null);
+    assert_eq!(note["email"], "bob@example.com");
+
+    // and bob's event falls back to the email
+    let (_, _, timeline) = send(db.app(), get("/api/v1/recipes/2/timeline", ALICE)).await;
+    assert_eq!(
+        timeline[1]["TimelineEvent"]["created_by_name"],
+        "bob@example.com"
+    );
+}
+
+#[tokio::test]
+async fn removed_reaction_is_deleted() {
+    let db = TestDb::new().await;
+    let state = db.state();
+    let app = app(routes(), state.clone());
+    let thumbs_up = serde_json::json!({"emoji": "👍"});
+
+    let remove = write(
+        &state,
+        Method::DELETE,
+        "/api/v1/notes/1/reactions",
+        thumbs_up.clone(),
+    );
+    let (status, _, reactions) = send(app.clone(), remove).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(reactions, serde_json::json!([]));
+    // there's no `deleted_at`, the row is gone
+    let client = db.connect().await;
+    let count: i64 = client
+        .query_one(r#"SELECT count(*) FROM "core_reaction""#, &[])
+        .await
+        .unwrap()
+        .get(0);
+    assert_eq!(count, 0);
+
+    // adding it back is a new row
+    let add = write(&state, Method::POST, "/api/v1/notes/1/reactions", thumbs_up);
+    let (status, _, reactions) = send(app, add).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(reactions.as_array().unwrap().len(), 1);
+    assert_ne!(reactions[0]["id"], 1);
+}
+
+/// What a test's subscriber logged.
+#[derive(Clone, Default)]
+struct Logs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn slow_query_is_logged_unless_the_threshold_is_0() {
+    let db = TestDb::new().await;
+    for (slow_query_ms, logged) in [("50", true), ("0", false)] {
+        let config =
+            Config::from_vars([("PG_DSN", "unused"), ("SLOW_QUERY_MS", slow_query_ms)]).unwrap();
+        let pool = db.pool(None, config.pool.slow_query);
+        let conn = pool.get().await.unwrap();
+
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::WARN)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+        conn.execute("SELECT pg_sleep(0.1)", &[])
+            .timed("sleep")
+            .await
+            .unwrap();
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            logs.contains("slow query") && logs.contains("query=\"sleep\""),
+            logged,
+            "SLOW_QUERY_MS={} logged {:?}",
+            slow_query_ms,
+            logs
+        );
+    }
+}
@@ -0,0 +1,597 @@
+//! The SQL, grouped by the table each query is mainly about. They're `&str`
+//! literals since [`PgConnection`](crate::db::PgConnection) caches the
+//! prepared statements by the literal's address.
+
+// Recipes, `core_recipe`
+
+pub const RECIPES: &str = r#"
+ SELECT
+	"core_recipe"."id",
+	"core_recipe"."name",
+	"core_recipe"."author",
+	"core_recipe"."source",
+	"core_recipe"."time",
+	"core_recipe"."servings",
+	"core_recipe"."edits",
+	"core_recipe"."modified",
+	"core_team"."id" "team_id",
+	"core_team"."name" "team_name",
+	"core_myuser"."id" "user_id",
+	COALESCE("core_myuser"."name", "core_myuser"."email") "user_name",
+	"core_recipe"."created",
+	"core_recipe"."archived_at",
+	"core_recipe"."tags"
+FROM
+	"core_recipe"
+	LEFT OUTER JOIN "core_myuser" ON ("core_recipe"."object_id" = "core_myuser"."id"
+		AND("core_recipe"."content_type_id" = 1))
+	LEFT OUTER JOIN "core_team" ON ("core_recipe"."object_id" = "core_team"."id"
+		AND("core_recipe"."content_type_id" = 20))
+WHERE ("core_recipe"."deleted_at" IS NULL
+	AND("core_myuser"."id" = $1
+		OR "core_team"."id" IN(
+			SELECT
+				U0. "team_id" FROM "core_membership" U0
+			WHERE (U0. "user_id" = $1
+				AND U0. "is_active")))
+	AND($4::timestamptz IS NULL
+		OR("core_recipe"."created", "core_recipe"."id") < ($4, $5)))
+ORDER BY
+	"core_recipe"."created" DESC,
+	"core_recipe"."id" DESC
+limit $2
+offset $3
+;
+        "#;
+
+pub const RECIPES_BY_ID: &str = r#"
+ SELECT
+	"core_recipe"."id",
+	"core_recipe"."name",
+	"core_recipe"."author",
+	"core_recipe"."source",
+	"core_recipe"."time",
+	"core_recipe"."servings",
+	"core_recipe"."edits",
+	"core_recipe"."modified",
+	"core_team"."id" "team_id",
+	"core_team"."name" "team_name",
+	"core_myuser"."id" "user_id",
+	COALESCE("core_myuser"."name", "core_myuser"."email") "user_name",
+	"core_recipe"."created",
+	"core_recipe"."archived_at",
+	"core_recipe"."tags"
+FROM
+	"core_recipe"
+	LEFT OUTER JOIN "core_myuser" ON ("core_recipe"."object_id" = "core_myuser"."id"
+		AND("core_recipe"."content_type_id" = 1))
+	LEFT OUTER JOIN "core_team" ON ("core_recipe"."object_id" = "core_team"."id"
+		AND("core_recipe"."content_type_id" = 20))
+WHERE ("core_recipe"."deleted_at" IS NULL
+	AND "core_recipe"."id" = any($2::int[])
+	AND("core_myuser"."id" = $1
+		OR "core_team"."id" IN(
+			SELECT
+				U0. "team_id" FROM "core_membership" U0
+			WHERE (U0. "user_id" = $1
+				AND U0. "is_active"))))
+;
+        "#;
+
+pub const RECIPE_COUNT: &str = r#"
+SELECT
+	count(*) "count"
+FROM
+	"core_recipe"
+	LEFT OUTER JOIN "core_myuser" ON ("core_recipe"."object_id" = "core_myuser"."id"
+		AND("core_recipe"."content_type_id" = 1))
+	LEFT OUTER JOIN "core_team" ON ("core_recipe"."object_id" = "core_team"."id"
+		AND("core_recipe"."content_type_id" = 20))
+WHERE ("core_recipe"."deleted_at" IS NULL
+	AND("core_myuser"."id" = $1
+		OR "core_team"."id" IN(
+			SELECT
+				U0. "team_id" FROM "core_membership" U0
+			WHERE (U0. "user_id" = $1
+				AND U0. "is_active"))))
+;
+        "#;
+
+pub const RANDOM_RECIPE: &str = r#"
+ SELECT
+	"core_recipe"."id",
+	"core_recipe"."name",
+	"core_recipe"."author",
+	"core_recipe"."source",
+	"core_recipe"."time",
+	"core_recipe"."servings",
+	"core_recipe"."edits",
+	"core_recipe"."modified",
+	"core_team"."id" "team_id",
+	"core_team"."name" "team_name",
+	"core_myuser"."id" "user_id",
+	COALESCE("core_myuser"."name", "core_myuser"."email") "user_name",
+	"core_recipe"."created",
+	"core_recipe"."archived_at",
+	"core_recipe"."tags"
+FROM
+	"core_recipe"
+	LEFT OUTER JOIN "core_myuser" ON ("core_recipe"."object_id" = "core_myuser"."id"
+		AND("core_recipe"."content_type_id" = 1))
+	LEFT OUTER JOIN "core_team" ON ("core_recipe"."object_id" = "core_team"."id"
+		AND("core_recipe"."content_type_id" = 20))
+WHERE ("core_recipe"."deleted_at" IS NULL
+	AND("core_myuser"."id" = $1
+		OR "core_team"."id" IN(
+			SELECT
+				U0. "team_id" FROM "core_membership" U0
+			WHERE (U0. "user_id" = $1
+				AND U0. "is_active"))))
+order by random()
+limit 1
+;
+        "#;
+
+pub const TAG_COUNTS: &str = r#"
+SELECT
+	"tags"."tag",
+	count(*) "count"
+FROM
+	"core_recipe"
+	LEFT OUTER JOIN "core_myuser" ON ("core_recipe"."object_id" = "core_myuser"."id"
+		AND("core_recipe"."content_type_id" = 1))
+	LEFT OUTER JOIN "core_team" ON ("core_recipe"."object_id" = "core_team"."id"
+		AND("core_recipe"."content_type_id" = 20))
+	CROSS JOIN LATERAL unnest("core_recipe"."tags") "tags" ("tag")
+WHERE ("core_recipe"."deleted_at" IS NULL
+	AND("core_myuser"."id" = $1
+		OR "core_team"."id" IN(
+			SELECT
+				U0. "team_id" FROM "core_membership" U0
+			WHERE (U0. "user_id" = $1
+				AND U0. "is_active"))))
+GROUP BY
+	"tags"."tag"
+ORDER BY
+	"count" DESC,
+	"tags"."tag" ASC;
+        "#;
+
+pub const RECIPE_EXISTS: &str = r#"
+SELECT
+	1
+FROM
+	"core_recipe"
+WHERE ("core_recipe"."deleted_at" IS NULL
+	AND "core_recipe"."id" = $1);
+        "#;
+
+pub const RECIPE_ACCESS: &str = r#"
+SELECT
+	1
+FROM
+	"core_recipe"
+	LEFT OUTER JOIN "core_myuser" ON ("core_recipe"."object_id" = "core_myuser"."id"
+		AND("core_recipe"."content_type_id" = 1))
+	LEFT OUTER JOIN "core_team" ON ("core_recipe"."object_id" = "core_team"."id"
+		AND("core_recipe"."content_type_id" = 20))
+WHERE ("core_recipe"."deleted_at" IS NULL
+	AND "core_recipe"."id" = $2
+	AND("core_myuser"."id" = $1
+		OR "core_team"."id" IN(
+			SELECT
+				U0. "team_id" FROM "core_membership" U0
+			WHERE (U0. "user_id" = $1
+				AND U0. "is_active"))))
+;
+        "#;
+
+pub const RECIPE_VERSION: &str = r#"
+SELECT
+	"core_recipe"."id",
+	"core_recipe"."modified",
+	"notes"."modified" "notes_modified",
+	"notes"."count" "notes_count",
+	"reactions"."modified" "reactions_modified",
+	"reactions"."count" "reactions_count",
+	"timeline"."created" "timeline_created",
+	"timeline"."count" "timeline_count",
+	"ingredients"."modified" "ingredients_modified",
+	"ingredients"."count" "ingredients_count",
+	"sections"."modified" "sections_modified",
+	"sections"."count" "sections_count",
+	"steps"."modified" "steps_modified",
+	"steps"."count" "steps_count"
+FROM
+	"core_recipe"
+	LEFT OUTER JOIN "core_myuser" ON ("core_recipe"."object_id" = "core_myuser"."id"
+		AND("core_recipe"."content_type_id" = 1))
+	LEFT OUTER JOIN "core_team" ON ("core_recipe"."object_id" = "core_team"."id"
+		AND("core_recipe"."content_type_id" = 20))
+	LEFT JOIN LATERAL (
+		SELECT
+			max("core_note"."modified") "modified",
+			count(*) "count"
+		FROM
+			"core_note"
+		WHERE ("core_note"."deleted_at" IS NULL
+			AND "core_note"."recipe_id" = "core_recipe"."id")) "notes" ON TRUE
+	LEFT JOIN LATERAL (
+		SELECT
+			max("core_reaction"."modified") "modified",
+			count(*) "count"
+		FROM
+			"core_reaction"
+			INNER JOIN "core_note" ON ("core_reaction"."note_id" = "core_note"."id")
+		WHERE
+			"core_note"."recipe_id" = "core_recipe"."id") "reactions" ON TRUE
+	LEFT JOIN LATERAL (
+		SELECT
+			max("timeline_event"."created") "created",
+			count(*) "count"
+		FROM
+			"timeline_event"
+		WHERE ("timeline_event"."deleted_at" IS NULL
+			AND "timeline_event"."recipe_id" = "core_recipe"."id")) "timeline" ON TRUE
+	LEFT JOIN LATERAL (
+		SELECT
+			max("core_ingredient"."modified") "modified",
+			count(*) "count"
+		FROM
+			"core_ingredient"
+		WHERE ("core_ingredient"."deleted_at" IS NULL
+			AND "core_ingredient"."recipe_id" = "core_recipe"."id")) "ingredients" ON TRUE
+	LEFT JOIN LATERAL (
+		SELECT
+			max("core_section"."modified") "modified",
+			count(*) "count"
+		FROM
+			"core_section"
+		WHERE ("core_section"."deleted_at" IS NULL
+			AND "core_section"."recipe_id" = "core_recipe"."id")) "sections" ON TRUE
+	LEFT JOIN LATERAL (
+		SELECT
+			max("core_step"."modified") "modified",
+			count(*) "count"
+		FROM
+			"core_step"
+		WHERE ("core_step"."deleted_at" IS NULL
+			AND "core_step"."recipe_id" = "core_recipe"."id")) "steps" ON TRUE
+WHERE ("core_recipe"."deleted_at" IS NULL
+	AND "core_recipe"."id" = $2
+	AND("core_myuser"."id" = $1
+		OR "core_team"."id" IN(
+			SELECT
+				U0. "team_id" FROM "core_membership" U0
+			WHERE (U0. "user_id" = $1
+				AND U0. "is_active"))))
+;
+        "#;
+
+pub const UPDATE_RECIPE: &str = r#"
+WITH "updated" AS (
+UPDATE
+	"core_recipe"
+SET
+	"name" = COALESCE($4, "core_recipe"."name"),
+	"author" = CASE WHEN $5::bool THEN $6 ELSE "core_recipe"."author" END,
+	"source" = CASE WHEN $7::bool THEN $8 ELSE "core_recipe"."source" END,
+	"time" = COALESCE($9, "core_recipe"."time"),
+	"servings" = COALESCE($10, "core_recipe"."servings"),
+	"tags" = COALESCE($11, "core_recipe"."tags"),
+	"modified" = $3,
+	"edits" = "core_recipe"."edits" + 1
+WHERE ("core_recipe"."deleted_at" IS NULL
+	AND "core_recipe"."id" = $2
+	AND(("core_recipe"."content_type_id" = 1
+			AND "core_recipe"."object_id" = $1)
+		OR("core_recipe"."content_type_id" = 20
+			AND "core_recipe"."object_id" IN(
+				SELECT
+					U0. "team_id" FROM "core_membership" U0
+				WHERE (U0. "user_id" = $1
+					AND U0. "is_active")))))
+RETURNING
+	"core_recipe".*
+)
+SELECT
+	"updated"."id",
+	"updated"."name",
+	"updated"."author",
+	"updated"."source",
+	"updated"."time",
+	"updated"."servings",
+	"core_team"."id" "team_id",
+	"core_team"."name" "team_name",
+	"core_myuser"."id" "user_id",
+	COALESCE("core_myuser"."name", "core_myuser"."email") "user_name",
+	"updated"."created",
+	"updated"."archived_at",
+	"updated"."tags"
+FROM
+	"updated"
+	LEFT OUTER JOIN "core_myuser" ON ("updated"."object_id" = "core_myuser"."id"
+		AND("updated"."content_type_id" = 1))
+	LEFT OUTER JOIN "core_team" ON ("updated"."object_id" = "core_team"."id"
+		AND("updated"."content_type_id" = 20));
+        "#;
+
+pub const SET_ARCHIVED: &str = r#"
+UPDATE
+	"core_recipe"
+SET
+	"archived_at" = CASE WHEN $3 THEN COALESCE("core_recipe"."archived_at", $4) ELSE NULL END,
+	"modified" = CASE WHEN ("core_recipe"."archived_at" IS NULL) = $3 THEN $4 ELSE "core_recipe"."modified" END
+WHERE ("core_recipe"."deleted_at" IS NULL
+	AND "core_recipe"."id" = $2
+	AND(("core_recipe"."content_type_id" = 1
+			AND "core_recipe"."object_id" = $1)
+		OR("core_recipe"."content_type_id" = 20
+			AND "core_recipe"."object_id" IN(
+				SELECT
+					U0. "team_id" FROM "core_membership" U0
+				WHERE (U0. "user_id" = $1
+					AND U0. "is_active")))))
+RETURNING
+	"core_recipe"."id",
+	"core_recipe"."name",
+	"core_recipe"."author",
+	"core_recipe"."source",
+	"core_recipe"."time",
+	"core_recipe"."servings",
+	"core_recipe"."created",
+	"core_recipe"."archived_at",
+	"core_recipe"."tags",
+	"core_recipe"."modified" = $4 "changed";
+        "#;
+
+pub const DELETE_RECIPE: &str = r#"
+UPDATE
+	"core_recipe"
+SET
+	"deleted_at" = $3
+WHERE ("core_recipe"."deleted_at" IS NULL
+	AND "core_recipe"."id" = $2
+	AND(("core_recipe"."content_type_id" = 1
+			AND "core_recipe"."object_id" = $1)
+		OR("core_recipe"."content_type_id" = 20
+			AND "core_recipe"."object_id" IN(
+				SELECT
+					U0. "team_id" FROM "core_membership" U0
+				WHERE (U0. "user_id" = $1
+					AND U0. "is_active")))));
+        "#;
+
+// Ingredients, `core_ingredient`
+
+pub const INGREDIENTS: &str = r#"
+SELECT
+	"core_ingredient"."id",
+	"core_ingredient"."position",
+	"core_ingredient"."quantity",
+	"core_ingredient"."name",
+	"core_ingredient"."description",
+	"core_ingredient"."recipe_id"
+FROM
+	"core_ingredient"
+WHERE ("core_ingredient"."deleted_at" IS NULL
+	AND "core_ingredient"."recipe_id" = any($1::int[]) )
+ORDER BY
+	"core_ingredient"."position" ASC;
+            "#;
+
+// Sections, `core_section`
+
+pub const SECTIONS: &str = r#"
+SELECT
+	"core_section"."id",
+	"core_section"."title",
+	"core_section"."position",
+	"core_section"."recipe_id"
+FROM
+	"core_section"
+WHERE ("core_section"."deleted_at" IS NULL
+	AND "core_section"."recipe_id" = any($1::int[]))
+ORDER BY
+	"core_section"."position" ASC;
+"#;
+
+// Steps, `core_step`
+
+pub const STEPS: &str = r#"
+SELECT
+	"core_step"."id",
+	"core_step"."text",
+	"core_step"."position",
+	"core_step"."recipe_id"
+FROM
+	"core_step"
+WHERE ("core_step"."deleted_at" IS NULL
+	AND "core_step"."recipe_id" = any($1::int[]) )
+ORDER BY
+	"core_step"."position" ASC;
+            "#;
+
+// Notes, `core_note`
+
+pub const NOTES: &str = r#"
+SELECT
+	"core_note"."id",
+	"core_note"."text",
+	"core_note"."modified",
+	"core_note"."created",
+	"core_note"."recipe_id",
+	"core_note"."last_modified_by_id",
+	"core_myuser"."email",
+	"core_myuser"."name",
+	"core_note"."created_by_id",
+	T4. "email",
+	T4. "name"
+FROM
+	"core_note"
+	LEFT OUTER JOIN "core_myuser" ON ("core_note"."last_modified_by_id" = "core_myuser"."id")
+INNER JOIN "core_myuser" T4 ON ("core_note"."created_by_id" = T4. "id")
+WHERE ("core_note"."deleted_at" IS NULL
+	AND "core_note"."recipe_id" = any($1::int[])
+	AND($2::timestamptz IS NULL
+		OR("core_note"."created", 1, "core_note"."id") < ($2, $3, $4)))
+ORDER BY
+	"core_note"."created" DESC,
+	"core_note"."id" DESC
+limit $5 offset $6;
+
+            "#;
+
+pub const NOTE_RECIPE: &str = r#"
+SELECT
+	"core_note"."recipe_id"
+FROM
+	"core_note"
+WHERE ("core_note"."deleted_at" IS NULL
+	AND "core_note"."id" = $1);
+        "#;
+
+pub const CREATE_NOTE: &str = r#"
+WITH "new_note" AS (
+INSERT INTO "core_note" ("text", "created", "modified", "recipe_id", "created_by_id", "last_modified_by_id")
+		VALUES($1, $2, $2, $3, $4, $4)
+	RETURNING
+		"core_note"."id", "core_note"."text", "core_note"."created", "core_note"."modified", "core_note"."created_by_id"
+)
+SELECT
+	"new_note"."id",
+	"new_note"."text",
+	"new_note"."modified",
+	"new_note"."created",
+	"core_myuser"."email",
+	"core_myuser"."name"
+FROM
+	"new_note"
+	INNER JOIN "core_myuser" ON ("new_note"."created_by_id" = "core_myuser"."id");
+        "#;
+
+// Reactions, `core_reaction`
+
+pub const REACTIONS: &str = r#"
+SELECT
+	"core_reaction"."id",
+	"core_reaction"."created",
+	"core_reaction"."modified",
+	"core_reaction"."emoji",
+	"core_reaction"."created_by_id",
+	"core_reaction"."note_id"
+FROM
+	"core_reaction"
+	INNER JOIN "core_note" ON ("core_reaction"."note_id" = "core_note"."id")
+WHERE
+	"core_note"."recipe_id" = any($1::int[])
+ORDER BY
+	"core_reaction"."created" DESC;
+            "#;
+
+pub const NOTE_REACTIONS: &str = r#"
+SELECT
+	"core_reaction"."id",
+	"core_reaction"."emoji",
+	"core_reaction"."created_by_id"
+FROM
+	"core_reaction"
+WHERE
+	"core_reaction"."note_id" = $1
+ORDER BY
+	"core_reaction"."created" DESC;
+        "#;
+
+pub const ADD_REACTION: &str = r#"
+INSERT INTO "core_reaction" ("emoji", "created_by_id", "note_id", "created", "modified")
+SELECT
+	$1::varchar, $2, $3, $4, $4
+WHERE
+	NOT EXISTS (
+		SELECT
+			1
+		FROM
+			"core_reaction"
+		WHERE ("core_reaction"."emoji" = $1
+			AND "core_reaction"."created_by_id" = $2
+			AND "core_reaction"."note_id" = $3));
+        "#;
+
+pub const REMOVE_REACTION: &str = r#"
+DELETE FROM "core_reaction"
+WHERE ("core_reaction"."emoji" = $1
+		AND "core_reaction"."created_by_id" = $2
+		AND "core_reaction"."note_id" = $3);
+        "#;
+
+pub const TOGGLE_REACTION: &str = r#"
+WITH "deleted" AS (
+DELETE FROM "core_reaction"
+	WHERE ("core_reaction"."emoji" = $1
+			AND "core_reaction"."created_by_id" = $2
+			AND "core_reaction"."note_id" = $3)
+	RETURNING
+		"core_reaction"."id"
+)
+INSERT INTO "core_reaction" ("emoji", "created_by_id", "note_id", "created", "modified")
+SELECT
+	$1, $2, $3, $4, $4
+WHERE
+	NOT EXISTS (
+		SELECT
+			1
+		FROM
+			"deleted");
+        "#;
+
+// Timeline events, `timeline_event`
+
+pub const TIMELINE_EVENTS: &str = r#"
+SELECT
+	"timeline_event"."id",
+	"timeline_event"."action",
+	"timeline_event"."created",
+	"timeline_event"."created_by_id",
+	COALESCE("core_myuser"."name", "core_myuser"."email") "created_by_name",
+	"timeline_event"."recipe_id"
+FROM
+	"timeline_event"
+	LEFT OUTER JOIN "core_myuser" ON ("timeline_event"."created_by_id" = "core_myuser"."id")
+WHERE ("timeline_event"."deleted_at" IS NULL
+	AND "timeline_event"."recipe_id" = any($1::int[])
+	AND($2::timestamptz IS NULL
+		OR("timeline_event"."created", 0, "timeline_event"."id") < ($2, $3, $4)))
+ORDER BY
+	"timeline_event"."created" DESC,
+	"timeline_event"."id" DESC
+limit $5;
+
+            "#;
+
+pub const CREATE_TIMELINE_EVENT: &str = r#"
+INSERT INTO "timeline_event" ("action", "created", "created_by_id", "recipe_id")
+	VALUES($1, $2, $3, $4);
+        "#;
+
+// Sessions, `user_sessions_session`
+
+pub const SESSION: &str = r#"
+SELECT
+	"user_sessions_session"."user_id",
+	"user_sessions_session"."expire_date",
+	"user_sessions_session"."expire_date" > $2::timestamptz "active"
+FROM
+	"user_sessions_session"
+WHERE ("user_sessions_session"."session_key" = $1
+    )
+LIMIT 1;"#;
+
+pub const SLIDE_SESSION: &str = r#"
+UPDATE
+	"user_sessions_session"
+SET
+	"expire_date" = $2
+WHERE ("user_sessions_session"."session_key" = $1
+	AND "user_sessions_session"."expire_date" > now()
+	AND "user_sessions_session"."expire_date" < $2);
+        "#;
@@ -0,0 +1,204 @@
+//! The session key from the `sessionid` cookie. Django stores either the raw
+//! key, the default, or with `SESSION_COOKIE_FORMAT=django-signed` the key
+//! signed the way `HttpResponse.set_signed_cookie` does it.
+//!
+//! With `SESSION_SLIDING=1` using a session pushes its expiry back, like
+//! Django's `SESSION_SAVE_EVERY_REQUEST`.
+
+use crate::db::ConnectionPool;
+use crate::error::AppError;
+use crate::queries;
+use axum::{
+    extract::State,
+    http::{header, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use axum_extra::extract::cookie::CookieJar;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Clone)]
+pub enum CookieFormat {
+    Raw,
+    DjangoSigned { secret_key: String },
+}
+
+/// How the `sessionid` cookie is read and, with `SESSION_SLIDING`, refreshed.
+/// Cheap to clone, it's shared through the `AppState`.
+#[derive(Clone)]
+pub struct SessionCookies {
+    format: Arc<CookieFormat>,
+    /// `None` unless `SESSION_SLIDING=1`
+    sliding: Option<Arc<Sliding>>,
+}
+
+impl SessionCookies {
+    /// `pool` is where sliding sessions are refreshed and `capacity` how many
+    /// refreshed sessions to remember, the same as the session cache's.
+    pub fn new(
+        format: CookieFormat,
+        sliding: Option<SlidingSettings>,
+        pool: ConnectionPool,
+        capacity: u64,
+    ) -> Self {
+        let sliding = sliding.map(|settings| {
+            let refreshed = moka::sync::Cache::builder()
+                .max_capacity(capacity)
+                .time_to_live(settings.interval)
+                .build();
+            Arc::new(Sliding {
+                settings,
+                pool,
+                refreshed,
+            })
+        });
+        SessionCookies {
+            format: Arc::new(format),
+            sliding,
+        }
+    }
+
+    /// A signed cookie that doesn't verify can't name a session, so it's
+    /// [`AppError::SessionInvalid`] like an unknown key.
+    pub fn session_key(&self, jar: &CookieJar) -> Result<String, AppError> {
+        let cookie = jar.get("sessionid").ok_or(AppError::SessionMissing)?;
+        match &*self.format {
+            CookieFormat::DjangoSigned { secret_key } => unsign(cookie.value(), secret_key)
+                .map(str::to_owned)
+                .ok_or(AppError::SessionInvalid),
+            CookieFormat::Raw => Ok(cookie.value().to_owned()),
+        }
+    }
+}
+
+/// `key:timestamp:signature` from Django's default `TimestampSigner`, salted
+/// with the cookie name. The timestamp isn't checked, the session's own
+/// `expire_date` is.
+fn unsign<'a>(cookie: &'a str, secret_key: &str) -> Option<&'a str> {
+    let (value, signature) = cookie.rsplit_once(':')?;
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+    // `salted_hmac`, the HMAC key is the hash of the salt and the secret
+    let key = Sha256::new()
+        .chain_update("sessionidsigner")
+        .chain_update("django.http.cookies")
+        .chain_update(secret_key)
+        .finalize();
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).ok()?;
+    mac.update(value.as_bytes());
+    // constant time
+    mac.verify_slice(&signature).ok()?;
+    let (session_key, _timestamp) = value.rsplit_once(':')?;
+    Some(session_key)
+}
+
+/// `SESSION_SLIDING`, how far to push a used session's expiry back and how
+/// often.
+#[derive(Clone, Copy)]
+pub struct SlidingSettings {
+    /// The new expiry is this long from now, Django's `SESSION_COOKIE_AGE`
+    pub window: Duration,
+    /// A session is refreshed at most once per this, so a busy client costs a
+    /// write every so often rather than one per request
+    pub interval: Duration,
+    /// Whether the refreshed cookie gets `Secure`, Django's
+    /// `SESSION_COOKIE_SECURE`
+    pub secure: bool,
+}
+
+struct Sliding {
+    settings: SlidingSettings,
+    pool: ConnectionPool,
+    /// The sessions refreshed in the last `interval`
+    refreshed: moka::sync::Cache<String, ()>,
+}
+
+tokio::task_local! {
+    /// Set by [`SessionCookies::touch`] so [`slide`] knows to send the cookie
+    /// again.
+    static REFRESHED: Cell<bool>;
+}
+
+impl SessionCookies {
+    /// Push the session's expiry back, called once it's known to be valid. A
+    /// no-op unless sessions slide.
+    ///
+    /// The update is spawned so the request doesn't wait on it, and it never
+    /// revives a session that has expired in the meantime. Two requests racing
+    /// past the debounce both write, which is harmless.
+    ///
+    /// A session cache entry keeps the expiry it was looked up with, so it can
+    /// run out before the database's does. That only costs an uncached lookup,
+    /// which sees the new expiry.
+    pub fn touch(&self, session_key: &str) {
+        let Some(sliding) = &self.sliding else {
+            return;
+        };
+        if sliding.refreshed.contains_key(session_key) {
+            return;
+        }
+        sliding.refreshed.insert(session_key.to_owned(), ());
+        // outside a request, e.g. a test, there's no response to put it on
+        let _ = REFRESHED.try_with(|refreshed| refreshed.set(true));
+
+        let pool = sliding.pool.clone();
+        let session_key = session_key.to_owned();
+        let expire_date = Utc::now()
+            + chrono::Duration::from_std(sliding.settings.window).expect("window fits in a chrono");
+        tokio::spawn(async move {
+            let refreshed = async {
+                let conn = pool.get().await?;
+                conn.execute(queries::SLIDE_SESSION, &[&session_key, &expire_date])
+                    .timed("slide_session")
+                    .await?;
+                Ok::<_, AppError>(())
+            };
+            if let Err(err) = refreshed.await {
+                // the session still works until its old expiry
+                tracing::warn!(?err, "couldn't refresh the session");
+            }
+        });
+    }
+}
+
+/// Send the `sessionid` cookie again with a new `Max-Age` when
+/// [`SessionCookies::touch`] refreshed the session, otherwise the browser
+/// would still drop it when the original one runs out.
+pub async fn slide<B>(
+    State(cookies): State<SessionCookies>,
+    jar: CookieJar,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(sliding) = &cookies.sliding else {
+        return next.run(request).await;
+    };
+    let (mut response, refreshed) = REFRESHED
+        .scope(Cell::new(false), async {
+            let response = next.run(request).await;
+            (response, REFRESHED.with(Cell::get))
+        })
+        .await;
+    let cookie = jar.get("sessionid").filter(|_| refreshed);
+    if let Some(cookie) = cookie {
+        // the same value, only the expiry changes
+        let mut set_cookie = format!(
+            "sessionid={}; Max-Age={}; Path=/; HttpOnly; SameSite=Lax",
+            cookie.value(),
+            sliding.settings.window.as_secs()
+        );
+        if sliding.settings.secure {
+            set_cookie.push_str("; Secure");
+        }
+        if let Ok(set_cookie) = HeaderValue::from_str(&set_cookie) {
+            response
+                .headers_mut()
+                .append(header::SET_COOKIE, set_cookie);
+        }
+    }
+    response
+}
@@ -0,0 +1,117 @@
+-- The tables the server reads and writes, trimmed from what Django's
+-- migrations create to the columns it uses. The pg-tests load it into a
+-- fresh database of their own.
+
+CREATE TABLE "core_myuser" (
+	"id" serial PRIMARY KEY,
+	"email" varchar(254) NOT NULL UNIQUE,
+	"name" varchar(255),
+	"password" varchar(128) NOT NULL,
+	"is_active" boolean NOT NULL,
+	"is_admin" boolean NOT NULL,
+	"created" timestamptz NOT NULL,
+	"modified" timestamptz NOT NULL
+);
+
+CREATE TABLE "user_sessions_session" (
+	"session_key" varchar(40) PRIMARY KEY,
+	"session_data" text NOT NULL,
+	"expire_date" timestamptz NOT NULL,
+	"user_id" integer REFERENCES "core_myuser",
+	"user_agent" varchar(200) NOT NULL,
+	"last_activity" timestamptz NOT NULL,
+	"ip" inet
+);
+
+CREATE TABLE "core_team" (
+	"id" serial PRIMARY KEY,
+	"name" varchar(255) NOT NULL
+);
+
+CREATE TABLE "core_membership" (
+	"id" serial PRIMARY KEY,
+	"team_id" integer NOT NULL REFERENCES "core_team",
+	"user_id" integer NOT NULL REFERENCES "core_myuser",
+	"is_active" boolean NOT NULL
+);
+
+-- owned by a user with content_type_id 1 or a team with 20
+CREATE TABLE "core_recipe" (
+	"id" serial PRIMARY KEY,
+	"name" varchar(255) NOT NULL,
+	"author" varchar(255),
+	"source" varchar(255),
+	"time" varchar(255) NOT NULL DEFAULT '',
+	"servings" varchar(255) NOT NULL DEFAULT '',
+	"tags" text[] NOT NULL DEFAULT '{}',
+	"edits" integer NOT NULL DEFAULT 0,
+	"object_id" integer NOT NULL,
+	"content_type_id" integer NOT NULL,
+	"created" timestamptz NOT NULL,
+	"modified" timestamptz NOT NULL,
+	"archived_at" timestamptz,
+	"deleted_at" timestamptz
+);
+
+CREATE TABLE "core_ingredient" (
+	"id" serial PRIMARY KEY,
+	"quantity" text NOT NULL,
+	"name" text NOT NULL,
+	"description" text NOT NULL,
+	"position" text NOT NULL,
+	"optional" boolean NOT NULL,
+	"recipe_id" integer NOT NULL REFERENCES "core_recipe",
+	"created" timestamptz NOT NULL,
+	"modified" timestamptz NOT NULL,
+	"deleted_at" timestamptz
+);
+
+CREATE TABLE "core_section" (
+	"id" serial PRIMARY KEY,
+	"title" text NOT NULL,
+	"position" text NOT NULL,
+	"recipe_id" integer NOT NULL REFERENCES "core_recipe",
+	"created" timestamptz NOT NULL,
+	"modified" timestamptz NOT NULL,
+	"deleted_at" timestamptz
+);
+
+CREATE TABLE "core_step" (
+	"id" serial PRIMARY KEY,
+	"text" text NOT NULL,
+	"position" text NOT NULL,
+	"recipe_id" integer NOT NULL REFERENCES "core_recipe",
+	"created" timestamptz NOT NULL,
+	"modified" timestamptz NOT NULL,
+	"deleted_at" timestamptz
+);
+
+CREATE TABLE "core_note" (
+	"id" serial PRIMARY KEY,
+	"text" text NOT NULL,
+	"created" timestamptz NOT NULL,
+	"modified" timestamptz NOT NULL,
+	"recipe_id" integer NOT NULL REFERENCES "core_recipe",
+	"created_by_id" integer NOT NULL REFERENCES "core_myuser",
+	"last_modified_by_id" integer REFERENCES "core_myuser",
+	"deleted_at" timestamptz
+);
+
+-- hard-deleted, there's no deleted_at
+CREATE TABLE "core_reaction" (
+	"id" serial PRIMARY KEY,
+	"emoji" varchar(255) NOT NULL,
+	"created_by_id" integer NOT NULL REFERENCES "core_myuser",
+	"note_id" integer NOT NULL REFERENCES "core_note",
+	"created" timestamptz NOT NULL,
+	"modified" timestamptz NOT NULL
+);
+
+CREATE TABLE "timeline_event" (
+	"id" serial PRIMARY KEY,
+	"action" text NOT NULL,
+	"created" timestamptz NOT NULL,
+	"created_by_id" integer REFERENCES "core_myuser",
+	"recipe_id" integer NOT NULL REFERENCES "core_recipe",
+	"deleted_at" timestamptz
+);
@@ -0,0 +1,78 @@
+-- What every pg-test starts from:
+--
+-- - alice, with a session, her own recipe and, through the team, a shared one
+-- - bob, in the team too, with no name so the email stands in for it
+-- - carol, with a session but no recipes
+-- - a recipe alice deleted, and one of bob's she can't see
+-- - an expired session of alice's
+
+INSERT INTO "core_myuser" ("id", "email", "name", "password", "is_active", "is_admin", "created", "modified")
+	VALUES
+	(1, 'alice@example.com', 'Alice', '!', TRUE, FALSE, '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z'),
+	(2, 'bob@example.com', NULL, '!', TRUE, FALSE, '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z'),
+	(3, 'carol@example.com', 'Carol', '!', TRUE, FALSE, '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z');
+
+INSERT INTO "user_sessions_session" ("session_key", "session_data", "expire_date", "user_id", "user_agent", "last_activity", "ip")
+	VALUES
+	('alicesession', '', '2099-01-01T00:00:00Z', 1, 'test', '2024-01-01T00:00:00Z', '127.0.0.1'),
+	('aliceexpired', '', '2000-01-01T00:00:00Z', 1, 'test', '2000-01-01T00:00:00Z', '127.0.0.1'),
+	('bobsession', '', '2099-01-01T00:00:00Z', 2, 'test', '2024-01-01T00:00:00Z', '127.0.0.1'),
+	('carolsession', '', '2099-01-01T00:00:00Z', 3, 'test', '2024-01-01T00:00:00Z', '127.0.0.1');
+
+INSERT INTO "core_team" ("id", "name")
+	VALUES (1, 'Test Kitchen');
+
+INSERT INTO "core_membership" ("id", "team_id", "user_id", "is_active")
+	VALUES (1, 1, 1, TRUE), (2, 1, 2, TRUE);
+
+INSERT INTO "core_recipe" ("id", "name", "author", "source", "time", "servings", "tags", "edits", "object_id", "content_type_id", "created", "modified", "archived_at", "deleted_at")
+	VALUES
+	(1, 'Pasta', 'Alice', 'https://example.com/pasta', '1 hour', '4 servings', '{dinner,quick}', 2, 1, 1, '2024-01-01T12:00:00Z', '2024-01-05T12:00:00Z', NULL, NULL),
+	(2, 'Curry', NULL, NULL, '45 min', '', '{dinner}', 0, 1, 20, '2024-01-02T12:00:00Z', '2024-01-02T12:00:00Z', NULL, NULL),
+	(3, 'Soup', 'Bob', NULL, '', '', '{}', 0, 2, 1, '2024-01-03T12:00:00Z', '2024-01-03T12:00:00Z', NULL, NULL),
+	(4, 'Toast', NULL, NULL, '', '', '{}', 0, 1, 1, '2024-01-04T12:00:00Z', '2024-01-04T12:00:00Z', NULL, '2024-01-04T13:00:00Z');
+
+-- the section sits between the ingredients at "1" and "10", which only works
+-- if positions aren't compared as plain strings
+INSERT INTO "core_ingredient" ("id", "quantity", "name", "description", "position", "optional", "recipe_id", "created", "modified", "deleted_at")
+	VALUES
+	(1, '1 lb', 'spaghetti', '', '1', FALSE, 1, '2024-01-01T12:00:00Z', '2024-01-01T12:00:00Z', NULL),
+	(2, '2 cups', 'tomatoes', 'crushed', '10', FALSE, 1, '2024-01-01T12:00:00Z', '2024-01-01T12:00:00Z', NULL),
+	(3, 'pinch', 'salt', '', '3', TRUE, 1, '2024-01-01T12:00:00Z', '2024-01-01T12:00:00Z', '2024-01-01T13:00:00Z'),
+	(4, '1 can', 'coconut milk', '', '1', FALSE, 2, '2024-01-02T12:00:00Z', '2024-01-02T12:00:00Z', NULL),
+	(5, '1', 'onion', '', '1', FALSE, 3, '2024-01-03T12:00:00Z', '2024-01-03T12:00:00Z', NULL);
+
+INSERT INTO "core_section" ("id", "title", "position", "recipe_id", "created", "modified", "deleted_at")
+	VALUES (1, 'Sauce', '2', 1, '2024-01-01T12:00:00Z', '2024-01-01T12:00:00Z', NULL);
+
+INSERT INTO "core_step" ("id", "text", "position", "recipe_id", "created", "modified", "deleted_at")
+	VALUES
+	(1, 'Boil the pasta.', '1', 1, '2024-01-01T12:00:00Z', '2024-01-01T12:00:00Z', NULL),
+	(2, 'Add the sauce.', '2', 1, '2024-01-01T12:00:00Z', '2024-01-01T12:00:00Z', NULL),
+	(3, 'Simmer.', '1', 2, '2024-01-02T12:00:00Z', '2024-01-02T12:00:00Z', NULL);
+
+INSERT INTO "core_note" ("id", "text", "created", "modified", "recipe_id", "created_by_id", "last_modified_by_id", "deleted_at")
+	VALUES
+	(1, 'Needs more garlic.', '2024-01-06T12:00:00Z', '2024-01-06T12:00:00Z', 1, 2, 2, NULL),
+	(2, 'Made it twice.', '2024-01-07T12:00:00Z', '2024-01-07T12:00:00Z', 2, 1, 1, NULL);
+
+INSERT INTO "core_reaction" ("id", "emoji", "created_by_id", "note_id", "created", "modified")
+	VALUES (1, '👍', 1, 1, '2024-01-06T13:00:00Z', '2024-01-06T13:00:00Z');
+
+INSERT INTO "timeline_event" ("id", "action", "created", "created_by_id", "recipe_id", "deleted_at")
+	VALUES
+	(1, 'created', '2024-01-01T12:00:00Z', 1, 1, NULL),
+	(2, 'created', '2024-01-02T12:00:00Z', 2, 2, NULL),
+	(3, 'created', '2024-01-03T12:00:00Z', 2, 3, NULL);
+
+-- the rows above have their ids set, so the sequences start past them
+SELECT setval(pg_get_serial_sequence('"core_myuser"', 'id'), 100);
+SELECT setval(pg_get_serial_sequence('"core_team"', 'id'), 100);
+SELECT setval(pg_get_serial_sequence('"core_membership"', 'id'), 100);
+SELECT setval(pg_get_serial_sequence('"core_recipe"', 'id'), 100);
+SELECT setval(pg_get_serial_sequence('"core_ingredient"', 'id'), 100);
+SELECT setval(pg_get_serial_sequence('"core_section"', 'id'), 100);
+SELECT setval(pg_get_serial_sequence('"core_step"', 'id'), 100);
+SELECT setval(pg_get_serial_sequence('"core_note"', 'id'), 100);
+SELECT setval(pg_get_serial_sequence('"core_reaction"', 'id'), 100);
+SELECT setval(pg_get_serial_sequence('"timeline_event"', 'id'), 100);